@@ -0,0 +1,565 @@
+//! Lightweight alternative to request/response peer discovery. Peers periodically broadcast tiny
+//! signed announcements with their short id and port, others passively collect them. No
+//! encryption is involved, key exchange is deferred until someone actually connects to the
+//! announced peer.
+//!
+//! Every beacon carries announcer's public signing key and a signature of its id and port, and
+//! `BeaconListener` drops beacons whose signature doesn't verify. The signing key travels in the
+//! beacon itself, so a valid signature only proves that whoever holds that key announced the id
+//! and port. Check `Beacon::is_signed_by()` with a key you trust, or verify peer's full public
+//! key once connected, e.g. with `Beacon::is_from()`.
+
+use futures::future;
+use peer::{self, FINGERPRINT_BYTES};
+use peer_discovery::{
+    bind_error, broadcast_sock, is_transient_recv_error, target_addrs, DiscoveryConfig,
+    DiscoveryError, DiscoveryMsg, MulticastMembership,
+};
+use priv_prelude::*;
+use safe_crypto::{PublicSignKey, SecretSignKey, Signature, PUBLIC_SIGN_KEY_BYTES};
+use serde::{Deserialize, Deserializer, Serializer};
+use socket;
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddrV4;
+use std::time::Instant;
+use tokio::net::UdpSocket;
+use tokio::timer::Interval;
+
+/// Beacons bigger than this are certainly invalid.
+const MAX_BEACON_SIZE: usize = 128;
+
+/// Beacon payload sent over the wire. Signing key and signature are encoded as their raw bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BeaconMsg {
+    /// Short id of announcing peer's public key.
+    pub id: [u8; FINGERPRINT_BYTES],
+    /// Port announcing peer accepts connections on.
+    pub port: u16,
+    /// Public key beacon is signed with.
+    #[serde(with = "sign_key_bytes")]
+    pub sign_pk: PublicSignKey,
+    /// Signature of `id` and `port`.
+    #[serde(with = "signature_bytes")]
+    pub signature: Signature,
+}
+
+impl BeaconMsg {
+    /// Constructs beacon announcing a given port and signs it.
+    pub fn new(
+        our_pk: &PublicEncryptKey,
+        our_port: u16,
+        sign_pk: &PublicSignKey,
+        sign_sk: &SecretSignKey,
+    ) -> Self {
+        let id = peer::key_id(our_pk);
+        Self {
+            id,
+            port: our_port,
+            sign_pk: *sign_pk,
+            signature: sign_sk.sign_detached(&signed_bytes(&id, our_port)),
+        }
+    }
+
+    /// Checks that beacon is signed by the key it carries.
+    pub fn is_valid(&self) -> bool {
+        self.sign_pk
+            .verify_detached(&self.signature, &signed_bytes(&self.id, self.port))
+    }
+}
+
+/// Bytes beacon signature is made of.
+fn signed_bytes(id: &[u8; FINGERPRINT_BYTES], port: u16) -> Vec<u8> {
+    let mut bytes = id.to_vec();
+    bytes.extend_from_slice(&port.to_be_bytes());
+    bytes
+}
+
+/// Serializes public signing key as raw bytes.
+mod sign_key_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(key: &PublicSignKey, serializer: S) -> Result<S::Ok, S::Error> {
+        key.into_bytes().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<PublicSignKey, D::Error> {
+        <[u8; PUBLIC_SIGN_KEY_BYTES]>::deserialize(deserializer).map(PublicSignKey::from_bytes)
+    }
+}
+
+/// Serializes signature as raw bytes. Serde only handles arrays of up to 32 elements, hence the
+/// signature is split in halves.
+mod signature_bytes {
+    use super::*;
+    use safe_crypto::SIGNATURE_BYTES;
+
+    const HALF: usize = SIGNATURE_BYTES / 2;
+
+    pub fn serialize<S: Serializer>(
+        signature: &Signature,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let bytes = signature.into_bytes();
+        let (mut first, mut second) = ([0; HALF], [0; HALF]);
+        first.copy_from_slice(&bytes[..HALF]);
+        second.copy_from_slice(&bytes[HALF..]);
+        (first, second).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Signature, D::Error> {
+        let (first, second) = <([u8; HALF], [u8; HALF])>::deserialize(deserializer)?;
+        let mut bytes = [0; SIGNATURE_BYTES];
+        bytes[..HALF].copy_from_slice(&first);
+        bytes[HALF..].copy_from_slice(&second);
+        Ok(Signature::from_bytes(bytes))
+    }
+}
+
+/// Beacon collected from the network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Beacon {
+    /// Short id of announcing peer's public key: first bytes of its hash.
+    pub id: [u8; FINGERPRINT_BYTES],
+    /// Beacon source IP and the port announcing peer accepts connections on.
+    pub addr: SocketAddr,
+    /// Public key beacon was signed with.
+    pub sign_pk: PublicSignKey,
+}
+
+impl Beacon {
+    /// Checks if beacon id matches given public key.
+    pub fn is_from(&self, pub_key: &PublicEncryptKey) -> bool {
+        self.id == peer::key_id(pub_key)
+    }
+
+    /// Checks if beacon was signed with a given key.
+    pub fn is_signed_by(&self, sign_pk: &PublicSignKey) -> bool {
+        self.sign_pk == *sign_pk
+    }
+}
+
+/// Broadcasts beacons announcing `our_port` to a given discovery port every `interval`. Beacons
+/// are signed with `our_sign_sk`. The returned future never resolves successfully, drop it to
+/// stop announcing.
+pub fn announce_beacons(
+    port: u16,
+    our_pk: &PublicEncryptKey,
+    our_sign_keys: (&PublicSignKey, &SecretSignKey),
+    our_port: u16,
+    interval: Duration,
+) -> impl Future<Item = (), Error = DiscoveryError> + Send {
+    announce_beacons_with_config(
+        port,
+        our_pk,
+        our_sign_keys,
+        our_port,
+        interval,
+        &DiscoveryConfig::default(),
+    )
+}
+
+/// Same as `announce_beacons()` but with custom configuration: beacons are sent to the same
+/// targets discovery requests are and with the same socket options.
+pub fn announce_beacons_with_config(
+    port: u16,
+    our_pk: &PublicEncryptKey,
+    our_sign_keys: (&PublicSignKey, &SecretSignKey),
+    our_port: u16,
+    interval: Duration,
+    config: &DiscoveryConfig,
+) -> impl Future<Item = (), Error = DiscoveryError> + Send {
+    let (sign_pk, sign_sk) = our_sign_keys;
+    let msg = DiscoveryMsg::Beacon(BeaconMsg::new(our_pk, our_port, sign_pk, sign_sk));
+    let config = config.clone();
+    future::result(AnnounceBeacons::new(port, msg, interval, config)).flatten()
+}
+
+/// Sends beacon to every target on each tick. Failing to send to one target doesn't stop the
+/// announcements: the error is logged and the rest of the targets are tried.
+struct AnnounceBeacons {
+    sock: UdpSocket,
+    packet: Vec<u8>,
+    port: u16,
+    config: DiscoveryConfig,
+    ticks: Interval,
+    // targets beacon wasn't sent to yet on this tick
+    targets: VecDeque<SocketAddr>,
+}
+
+impl AnnounceBeacons {
+    fn new(
+        port: u16,
+        msg: DiscoveryMsg,
+        interval: Duration,
+        config: DiscoveryConfig,
+    ) -> Result<Self, DiscoveryError> {
+        let packet = msg.serialize()?;
+        let sock = broadcast_sock(&config.socket_options).map_err(DiscoveryError::Io)?;
+        Ok(Self {
+            sock,
+            packet,
+            port,
+            config,
+            ticks: Interval::new(Instant::now(), interval),
+            targets: VecDeque::new(),
+        })
+    }
+
+    fn list_targets(&self) -> VecDeque<SocketAddr> {
+        match target_addrs(self.port, &self.config) {
+            Ok(targets) => targets.into_iter().collect(),
+            Err(e) => {
+                info!("Failed to list beacon targets: {}", e);
+                VecDeque::new()
+            }
+        }
+    }
+}
+
+impl Future for AnnounceBeacons {
+    type Item = ();
+    type Error = DiscoveryError;
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        loop {
+            if let Some(addr) = self.targets.front().cloned() {
+                match self.sock.poll_send_to(&self.packet, &addr) {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(_)) => (),
+                    Err(e) => info!("Failed to send beacon to {}: {}", addr, e),
+                }
+                let _ = self.targets.pop_front();
+                continue;
+            }
+            let tick = self
+                .ticks
+                .poll()
+                .map_err(|e| DiscoveryError::Io(io::Error::new(io::ErrorKind::Other, e)))?;
+            match tick {
+                Async::Ready(Some(_)) => self.targets = self.list_targets(),
+                Async::Ready(None) => return Ok(Async::Ready(())),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+/// Starts collecting beacons sent to a given port. Use port 0 to bind to a random port.
+pub fn listen_for_beacons(port: u16) -> Result<BeaconListener, DiscoveryError> {
+    listen_for_beacons_with_config(port, &DiscoveryConfig::default())
+}
+
+/// Same as `listen_for_beacons()` but with custom configuration: the listener binds to
+/// `config.bind_ip` with `config.socket_options` and joins `config.multicast_group` like
+/// `DiscoveryServer` does. Set `socket_options.reuse_addr` on both to share the discovery port
+/// with a server.
+pub fn listen_for_beacons_with_config(
+    port: u16,
+    config: &DiscoveryConfig,
+) -> Result<BeaconListener, DiscoveryError> {
+    let sock = socket::bind_udp(
+        &SocketAddr::V4(SocketAddrV4::new(
+            config.bind_ip.unwrap_or_else(|| ipv4!("0.0.0.0")),
+            port,
+        )),
+        &config.socket_options,
+    ).map_err(|e| bind_error(port, e))?;
+    let multicast = MulticastMembership::join(&sock, config).map_err(DiscoveryError::Io)?;
+    let port = sock.local_addr().map_err(DiscoveryError::Io)?.port();
+    Ok(BeaconListener {
+        sock,
+        port,
+        multicast,
+        buf: vec![0; MAX_BEACON_SIZE],
+    })
+}
+
+/// Stream of beacons received from the network. Beacons with invalid signatures are dropped. It
+/// never ends on its own.
+pub struct BeaconListener {
+    sock: UdpSocket,
+    port: u16,
+    multicast: Option<MulticastMembership>,
+    buf: Vec<u8>,
+}
+
+impl BeaconListener {
+    /// Returns port beacons are collected on.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl Stream for BeaconListener {
+    type Item = Beacon;
+    type Error = DiscoveryError;
+
+    fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
+        loop {
+            let (bytes_read, sender_addr) = match self.sock.poll_recv_from(&mut self.buf) {
+                Ok(Async::Ready(res)) => res,
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(ref e) if is_transient_recv_error(e) => continue,
+                Err(e) => return Err(DiscoveryError::Io(e)),
+            };
+            match DiscoveryMsg::deserialize(&self.buf[..bytes_read]) {
+                Ok(DiscoveryMsg::Beacon(ref msg)) if !msg.is_valid() => {
+                    debug!("Beacon with invalid signature from {}", sender_addr)
+                }
+                Ok(DiscoveryMsg::Beacon(msg)) => {
+                    return Ok(Async::Ready(Some(Beacon {
+                        id: msg.id,
+                        addr: SocketAddr::new(sender_addr.ip(), msg.port),
+                        sign_pk: msg.sign_pk,
+                    })))
+                }
+                _ => debug!("Invalid beacon from {}", sender_addr),
+            }
+        }
+    }
+}
+
+impl Drop for BeaconListener {
+    fn drop(&mut self) {
+        if let Some(ref multicast) = self.multicast {
+            multicast.leave(&self.sock);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest2::prelude::*;
+    use peer_discovery::DiscoveryServer;
+    use safe_crypto::gen_sign_keypair;
+    use socket::SocketOptions;
+    use tokio::runtime::current_thread::Runtime;
+
+    #[test]
+    fn beacon_message_is_tiny() {
+        let (pub_key, _) = gen_encrypt_keypair();
+        let (sign_pk, sign_sk) = gen_sign_keypair();
+        let msg = DiscoveryMsg::Beacon(BeaconMsg::new(&pub_key, 1234, &sign_pk, &sign_sk));
+
+        let packet = unwrap!(msg.serialize());
+
+        // header, variant, id, port, signing key and signature
+        assert_that!(packet.len(), eq(3 + 4 + 4 + 2 + 32 + 64));
+        assert_that!(packet.len(), leq(MAX_BEACON_SIZE));
+    }
+
+    mod beacon_msg {
+        use super::*;
+
+        #[test]
+        fn it_survives_serialization() {
+            let (pub_key, _) = gen_encrypt_keypair();
+            let (sign_pk, sign_sk) = gen_sign_keypair();
+            let msg = BeaconMsg::new(&pub_key, 1234, &sign_pk, &sign_sk);
+
+            let packet = unwrap!(DiscoveryMsg::Beacon(msg).serialize());
+
+            match unwrap!(DiscoveryMsg::deserialize(&packet)) {
+                DiscoveryMsg::Beacon(deserialized) => {
+                    assert_that!(deserialized, eq(msg));
+                    assert_that!(deserialized.is_valid(), is(true));
+                }
+                msg => panic!("Unexpected message: {:?}", msg),
+            }
+        }
+
+        #[test]
+        fn it_is_invalid_when_port_is_altered() {
+            let (pub_key, _) = gen_encrypt_keypair();
+            let (sign_pk, sign_sk) = gen_sign_keypair();
+            let mut msg = BeaconMsg::new(&pub_key, 1234, &sign_pk, &sign_sk);
+
+            msg.port = 4321;
+
+            assert_that!(msg.is_valid(), is(false));
+        }
+
+        #[test]
+        fn it_is_invalid_when_signed_with_other_key() {
+            let (pub_key, _) = gen_encrypt_keypair();
+            let (sign_pk, _) = gen_sign_keypair();
+            let (_, other_sign_sk) = gen_sign_keypair();
+
+            let msg = BeaconMsg::new(&pub_key, 1234, &sign_pk, &other_sign_sk);
+
+            assert_that!(msg.is_valid(), is(false));
+        }
+    }
+
+    #[test]
+    fn beacon_is_from_peer_whose_key_id_it_carries() {
+        let (pub_key, _) = gen_encrypt_keypair();
+        let (other_pub_key, _) = gen_encrypt_keypair();
+        let (sign_pk, _) = gen_sign_keypair();
+        let beacon = Beacon {
+            id: peer::key_id(&pub_key),
+            addr: addr!("192.168.1.100:1234"),
+            sign_pk,
+        };
+
+        assert_that!(beacon.is_from(&pub_key), is(true));
+        assert_that!(beacon.is_from(&other_pub_key), is(false));
+    }
+
+    #[test]
+    fn beacons_are_announced_to_configured_targets() {
+        let mut evloop = unwrap!(Runtime::new());
+
+        let listener = unwrap!(listen_for_beacons(0));
+        let (our_pk, _) = gen_encrypt_keypair();
+        let (sign_pk, sign_sk) = gen_sign_keypair();
+        let config = DiscoveryConfig {
+            loopback_ports: vec![listener.port()],
+            ..Default::default()
+        };
+        let announce = announce_beacons_with_config(
+            0,
+            &our_pk,
+            (&sign_pk, &sign_sk),
+            1234,
+            Duration::from_millis(100),
+            &config,
+        );
+
+        let task = listener
+            .take(2)
+            .collect()
+            .with_timeout(Duration::from_secs(10))
+            .map(|beacons_opt| unwrap!(beacons_opt, "No beacons received"))
+            .while_driving(announce);
+
+        match evloop.block_on(task) {
+            Ok((beacons, _announce_task)) => {
+                assert_that!(beacons[0].is_from(&our_pk), is(true));
+                assert_that!(beacons[0].is_signed_by(&sign_pk), is(true));
+                assert_that!(beacons[0].addr, eq(addr!("127.0.0.1:1234")));
+            }
+            _ => panic!("Beacon collection failed"),
+        }
+    }
+
+    #[test]
+    fn it_keeps_announcing_when_sending_to_some_target_fails() {
+        let mut evloop = unwrap!(Runtime::new());
+
+        let listener = unwrap!(listen_for_beacons(0));
+        let (our_pk, _) = gen_encrypt_keypair();
+        let (sign_pk, sign_sk) = gen_sign_keypair();
+        let config = DiscoveryConfig {
+            // sending to port 0 fails
+            loopback_ports: vec![0, listener.port()],
+            ..Default::default()
+        };
+        let announce = announce_beacons_with_config(
+            0,
+            &our_pk,
+            (&sign_pk, &sign_sk),
+            1234,
+            Duration::from_millis(100),
+            &config,
+        );
+
+        let task = listener
+            .take(2)
+            .collect()
+            .with_timeout(Duration::from_secs(10))
+            .map(|beacons_opt| unwrap!(beacons_opt, "No beacons received"))
+            .while_driving(announce);
+
+        match evloop.block_on(task) {
+            Ok((beacons, _announce_task)) => assert_that!(beacons.len(), eq(2)),
+            _ => panic!("Beacon collection failed"),
+        }
+    }
+
+    #[test]
+    fn announced_beacons_are_collected_on_lan() {
+        let mut evloop = unwrap!(Runtime::new());
+
+        let listener = unwrap!(listen_for_beacons(0));
+        let (our_pk, _) = gen_encrypt_keypair();
+        let (sign_pk, sign_sk) = gen_sign_keypair();
+        let announce = announce_beacons(
+            listener.port(),
+            &our_pk,
+            (&sign_pk, &sign_sk),
+            1234,
+            Duration::from_millis(100),
+        );
+
+        let task = listener
+            .take(1)
+            .collect()
+            .with_timeout(Duration::from_secs(10))
+            .map(|beacons_opt| unwrap!(beacons_opt, "No beacons received"))
+            .while_driving(announce);
+
+        match evloop.block_on(task) {
+            Ok((beacons, _announce_task)) => {
+                assert_that!(beacons[0].is_from(&our_pk), is(true));
+                assert_that!(beacons[0].addr.port(), eq(1234));
+            }
+            _ => panic!("Beacon collection failed"),
+        }
+    }
+
+    #[test]
+    fn listener_drops_beacons_with_invalid_signature() {
+        let mut evloop = unwrap!(Runtime::new());
+
+        let listener = unwrap!(listen_for_beacons(0));
+        let listener_addr = SocketAddr::new(ip!("127.0.0.1"), listener.port());
+        let (our_pk, _) = gen_encrypt_keypair();
+        let (sign_pk, sign_sk) = gen_sign_keypair();
+        let mut forged = BeaconMsg::new(&our_pk, 1234, &sign_pk, &sign_sk);
+        forged.port = 4321;
+        let valid = BeaconMsg::new(&our_pk, 1234, &sign_pk, &sign_sk);
+
+        let sock = unwrap!(::std::net::UdpSocket::bind("127.0.0.1:0"));
+        for msg in &[forged, valid] {
+            let packet = unwrap!(DiscoveryMsg::Beacon(*msg).serialize());
+            let _ = unwrap!(sock.send_to(&packet, listener_addr));
+        }
+
+        let task = listener
+            .into_future()
+            .map(|(beacon_opt, _listener)| unwrap!(beacon_opt))
+            .map_err(|(e, _listener)| e)
+            .with_timeout(Duration::from_secs(10));
+        let beacon = unwrap!(unwrap!(evloop.block_on(task)), "No beacons received");
+
+        assert_that!(beacon.addr.port(), eq(1234));
+    }
+
+    #[test]
+    fn listener_can_share_port_with_discovery_server_when_reuse_addr_is_set() {
+        let (our_pk, _) = gen_encrypt_keypair();
+        let config = DiscoveryConfig {
+            socket_options: SocketOptions {
+                reuse_addr: Some(true),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let server = unwrap!(DiscoveryServer::with_config(
+            0,
+            vec![addr!("192.168.1.100:1234")],
+            &our_pk,
+            &config
+        ));
+        let port = server.port();
+
+        let listener = unwrap!(listen_for_beacons_with_config(port, &config));
+
+        assert_that!(listener.port(), eq(port));
+    }
+}
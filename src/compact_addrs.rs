@@ -0,0 +1,199 @@
+//! Compact wire encoding of socket address lists. Default bincode encoding spends 4 bytes on the
+//! address family tag of every address, so IPv4 address takes 10 bytes and IPv6 - 22 bytes. Here
+//! addresses are grouped by family and each group is prefixed with its one byte length:
+//!
+//! ```text
+//! IPv4 count (1 byte) | IPv4 (4 bytes) | port (2 bytes, big endian) | ...
+//! IPv6 count (1 byte) | IPv6 (16 bytes) | port (2 bytes, big endian) | ...
+//! ```
+//!
+//! so IPv4 addresses take 6 bytes, IPv6 - 18 bytes and the list itself 2 bytes. Decoded list has
+//! IPv4 addresses first, otherwise the order is kept. Lists with more than 255 addresses of the
+//! same family can't be encoded.
+
+use serde::de::{Error as DeError, SeqAccess, Visitor};
+use serde::ser::{Error as SerError, SerializeTuple};
+use serde::{Deserializer, Serializer};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Maximum number of addresses of a single family.
+const MAX_GROUP_LEN: usize = 255;
+/// Size of the longest encoded list.
+const MAX_ENCODED_LEN: usize = 2 + MAX_GROUP_LEN * (6 + 18);
+
+pub fn serialize<S: Serializer>(addrs: &[SocketAddr], serializer: S) -> Result<S::Ok, S::Error> {
+    let buf = encode(addrs).ok_or_else(|| S::Error::custom("too many addresses"))?;
+    // tuples have no length prefix, the counts are enough to decode them
+    let mut tuple = serializer.serialize_tuple(buf.len())?;
+    for byte in &buf {
+        tuple.serialize_element(byte)?;
+    }
+    tuple.end()
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<SocketAddr>, D::Error> {
+    deserializer.deserialize_tuple(MAX_ENCODED_LEN, AddrsVisitor)
+}
+
+/// Reads address groups byte by byte, only as many bytes as their counts say.
+struct AddrsVisitor;
+
+impl<'de> Visitor<'de> for AddrsVisitor {
+    type Value = Vec<SocketAddr>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "compact address list")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut next_byte = || {
+            seq.next_element::<u8>()?
+                .ok_or_else(|| A::Error::custom("malformed address list"))
+        };
+        let mut buf = Vec::new();
+        for &ip_len in &[4, 16] {
+            let count = next_byte()?;
+            buf.push(count);
+            for _ in 0..usize::from(count) * (ip_len + 2) {
+                buf.push(next_byte()?);
+            }
+        }
+        decode(&buf).ok_or_else(|| A::Error::custom("malformed address list"))
+    }
+}
+
+fn encode(addrs: &[SocketAddr]) -> Option<Vec<u8>> {
+    let (v4, v6): (Vec<&SocketAddr>, Vec<_>) = addrs.iter().partition(|addr| addr.is_ipv4());
+    if v4.len() > MAX_GROUP_LEN || v6.len() > MAX_GROUP_LEN {
+        return None;
+    }
+    let mut buf = Vec::with_capacity(2 + v4.len() * 6 + v6.len() * 18);
+    for group in &[v4, v6] {
+        buf.push(group.len() as u8);
+        for addr in group {
+            match addr.ip() {
+                IpAddr::V4(ip) => buf.extend_from_slice(&ip.octets()),
+                IpAddr::V6(ip) => buf.extend_from_slice(&ip.octets()),
+            }
+            buf.push((addr.port() >> 8) as u8);
+            buf.push(addr.port() as u8);
+        }
+    }
+    Some(buf)
+}
+
+fn decode(buf: &[u8]) -> Option<Vec<SocketAddr>> {
+    let mut addrs = Vec::new();
+    let mut rest = buf;
+    for &ip_len in &[4, 16] {
+        let (&count, tail) = rest.split_first()?;
+        rest = tail;
+        for _ in 0..count {
+            if rest.len() < ip_len + 2 {
+                return None;
+            }
+            let (ip, tail) = rest.split_at(ip_len);
+            let ip = if ip_len == 4 {
+                IpAddr::V4(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]))
+            } else {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(ip);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            let port = (u16::from(tail[0]) << 8) | u16::from(tail[1]);
+            addrs.push(SocketAddr::new(ip, port));
+            rest = &tail[2..];
+        }
+    }
+    if rest.is_empty() {
+        Some(addrs)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bincode;
+    use hamcrest2::prelude::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Addrs(#[serde(with = "super")] Vec<SocketAddr>);
+
+    #[test]
+    fn ipv4_and_ipv6_addresses_are_encoded_compactly() {
+        let v4 = unwrap!(encode(&[addr!("192.168.1.100:1234")]));
+        let v6 = unwrap!(encode(&[addr!("[fe80::1]:1234")]));
+
+        assert_that!(v4.len(), eq(2 + 6));
+        assert_that!(v6.len(), eq(2 + 18));
+    }
+
+    #[test]
+    fn serialized_list_has_no_length_prefix() {
+        let addrs = Addrs(vec![addr!("192.168.1.100:1234"), addr!("[fe80::1]:1234")]);
+
+        let buf = unwrap!(bincode::serialize(&addrs));
+
+        assert_that!(buf.len(), eq(2 + 6 + 18));
+        assert_that!(unwrap!(bincode::deserialize::<Addrs>(&buf)), eq(addrs));
+    }
+
+    #[test]
+    fn mixed_address_lists_round_trip_with_ipv4_addresses_first() {
+        let lists = vec![
+            (vec![], vec![]),
+            (
+                vec![addr!("192.168.1.100:1234"), addr!("10.0.0.1:65535")],
+                vec![addr!("192.168.1.100:1234"), addr!("10.0.0.1:65535")],
+            ),
+            (
+                vec![addr!("[fe80::1]:1234"), addr!("[2001:db8::ff]:1")],
+                vec![addr!("[fe80::1]:1234"), addr!("[2001:db8::ff]:1")],
+            ),
+            (
+                vec![
+                    addr!("[fe80::1]:1234"),
+                    addr!("192.168.1.100:1234"),
+                    addr!("[::1]:0"),
+                    addr!("127.0.0.1:5000"),
+                ],
+                vec![
+                    addr!("192.168.1.100:1234"),
+                    addr!("127.0.0.1:5000"),
+                    addr!("[fe80::1]:1234"),
+                    addr!("[::1]:0"),
+                ],
+            ),
+        ];
+        for (addrs, decoded) in lists {
+            let buf = unwrap!(encode(&addrs));
+            assert_that!(decode(&buf), eq(Some(decoded)));
+        }
+    }
+
+    #[test]
+    fn encode_fails_when_there_are_too_many_addresses_of_one_family() {
+        let addrs = vec![addr!("192.168.1.100:1234"); MAX_GROUP_LEN + 1];
+
+        assert_that!(encode(&addrs), none());
+    }
+
+    #[test]
+    fn decode_rejects_malformed_input() {
+        let invalid: [&[u8]; 5] = [
+            &[],
+            &[1, 192, 168, 1, 100, 4, 210],
+            &[1, 192, 168, 1, 100, 4],
+            &[0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+            &[0, 0, 0],
+        ];
+        for buf in &invalid {
+            assert_that!(decode(buf), none());
+        }
+    }
+}
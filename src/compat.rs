@@ -0,0 +1,101 @@
+//! futures 0.3 flavored peer discovery API, so it can be used with async/await. Enabled with
+//! `futures03` feature.
+//!
+//! These are thin wrappers around the futures 0.1 API: under the hood discovery still uses tokio
+//! 0.1 sockets and timers, hence returned futures and streams must be driven within tokio 0.1
+//! runtime, e.g. with `tokio-compat`.
+
+use futures03::compat::{Future01CompatExt, Stream01CompatExt};
+use futures03::{Future as Future03, Stream as Stream03};
+use peer_discovery::{self, DiscoveryConfig, DiscoveryError, DiscoveryServer};
+use priv_prelude::*;
+
+/// Same as `::discover_peers()` but returns futures 0.3 stream.
+pub fn discover_peers(
+    port: u16,
+    our_addrs: Vec<SocketAddr>,
+    our_pk: &PublicEncryptKey,
+    our_sk: &SecretEncryptKey,
+) -> Result<impl Stream03<Item = Result<Vec<PeerInfo>, DiscoveryError>> + Send, DiscoveryError> {
+    discover_peers_with_config(port, our_addrs, our_pk, our_sk, &DiscoveryConfig::default())
+}
+
+/// Same as `::discover_peers_with_config()` but returns futures 0.3 stream.
+pub fn discover_peers_with_config(
+    port: u16,
+    our_addrs: Vec<SocketAddr>,
+    our_pk: &PublicEncryptKey,
+    our_sk: &SecretEncryptKey,
+    config: &DiscoveryConfig,
+) -> Result<impl Stream03<Item = Result<Vec<PeerInfo>, DiscoveryError>> + Send, DiscoveryError> {
+    peer_discovery::discover_peers_with_config(port, our_addrs, our_pk, our_sk, config)
+        .map(Stream01CompatExt::compat)
+}
+
+/// Same as `::shout_for_peers()` but returns futures 0.3 stream.
+pub fn shout_for_peers(
+    port: u16,
+    our_pk: &PublicEncryptKey,
+    our_sk: &SecretEncryptKey,
+) -> impl Stream03<Item = Result<Vec<PeerInfo>, DiscoveryError>> + Send {
+    shout_for_peers_with_config(port, our_pk, our_sk, &DiscoveryConfig::default())
+}
+
+/// Same as `::shout_for_peers_with_config()` but returns futures 0.3 stream.
+pub fn shout_for_peers_with_config(
+    port: u16,
+    our_pk: &PublicEncryptKey,
+    our_sk: &SecretEncryptKey,
+    config: &DiscoveryConfig,
+) -> impl Stream03<Item = Result<Vec<PeerInfo>, DiscoveryError>> + Send {
+    peer_discovery::shout_for_peers_with_config(port, our_pk, our_sk, config).compat()
+}
+
+/// Turns discovery server into futures 0.3 future that runs it. Like the server itself, the
+/// future only resolves on error.
+pub fn serve(
+    server: DiscoveryServer,
+) -> impl Future03<Output = Result<Void, DiscoveryError>> + Send {
+    server.compat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures03::future::{self, Either};
+    use futures03::{FutureExt as FutureExt03, TryFutureExt, TryStreamExt};
+    use hamcrest2::prelude::*;
+    use tokio::runtime::current_thread::Runtime;
+
+    #[test]
+    fn shout_for_peers_yields_peers_as_futures03_stream() {
+        let mut evloop = unwrap!(Runtime::new());
+
+        let (server_pk, _server_sk) = gen_encrypt_keypair();
+        let server = unwrap!(DiscoveryServer::new(
+            0,
+            vec![addr!("192.168.1.100:1234")],
+            &server_pk,
+        ));
+        let server_port = server.port();
+
+        let (our_pk, our_sk) = gen_encrypt_keypair();
+        let find_peers = shout_for_peers(server_port, &our_pk, &our_sk).try_collect::<Vec<_>>();
+        let task = future::select(Box::pin(find_peers), Box::pin(serve(server)))
+            .map(|either| match either {
+                Either::Left((res, _server)) => res,
+                Either::Right((res, _find_peers)) => Err(unwrap!(res.err())),
+            }).boxed()
+            .compat()
+            .with_timeout(Duration::from_secs(10));
+
+        let their_addrs = unwrap!(unwrap!(evloop.block_on(task)));
+        assert_that!(
+            their_addrs,
+            eq(vec![vec![PeerInfo::new(
+                addr!("192.168.1.100:1234"),
+                server_pk
+            )]])
+        );
+    }
+}
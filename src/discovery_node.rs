@@ -0,0 +1,331 @@
+//! Peer that both answers and sends discovery requests through a single UDP socket, so only one
+//! port has to be open in the firewall.
+
+use futures::task;
+use peer_discovery::{
+    bind_error, decrypt_response, is_acceptable_peer, is_fully_sent, poll_recv_all, poll_send_all,
+    probe_addrs, target_addrs, BlockedTransmit, DiscoveryConfig, DiscoveryError, DiscoveryMsg,
+    MulticastMembership,
+};
+use priv_prelude::*;
+use server_core::{ServerCore, Transmit};
+use socket;
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddrV4;
+use std::time::Instant;
+use tokio::net::UdpSocket;
+#[cfg(feature = "wire-tap")]
+use wire_tap::{self, Direction, WireTap};
+
+/// Discovery server and client sharing one socket. Requests are sent from the port the node
+/// listens on, so peers respond to that same port and node tells responses from requests by
+/// trying to decrypt them: only responses meant for us decrypt with our secret key.
+///
+/// `DiscoveryNode` is a stream of discovered peers that never ends on its own. Requests are
+/// broadcast once on construction, call `shout()` to broadcast them again.
+pub struct DiscoveryNode {
+    sock: UdpSocket,
+    port: u16,
+    core: ServerCore,
+    our_pk: PublicEncryptKey,
+    our_sk: SecretEncryptKey,
+    config: DiscoveryConfig,
+    /// Serialized discovery request.
+    request: Vec<u8>,
+    /// Our requests waiting to be sent, they go out before responses.
+    requests: VecDeque<Transmit>,
+    blocked: Option<BlockedTransmit>,
+    multicast: Option<MulticastMembership>,
+    /// Peers from received responses not yet yielded.
+    found: VecDeque<Vec<PeerInfo>>,
+    #[cfg(feature = "wire-tap")]
+    wire_tap: Option<WireTap>,
+}
+
+impl DiscoveryNode {
+    /// Binds discovery node to a given port and broadcasts discovery requests to that same port
+    /// on other hosts. Node responds to requests with `our_addrs`, see `DiscoveryServer::new()`.
+    pub fn new(
+        port: u16,
+        our_addrs: Vec<SocketAddr>,
+        our_pk: &PublicEncryptKey,
+        our_sk: &SecretEncryptKey,
+        config: &DiscoveryConfig,
+    ) -> Result<Self, DiscoveryError> {
+        let our_addrs = if config.probe_our_addrs {
+            probe_addrs(our_addrs)
+        } else {
+            our_addrs
+        };
+        let core = ServerCore::new(our_addrs, our_pk, config)?;
+        let request = DiscoveryMsg::serialized_request(*our_pk)?;
+        let sock = socket::bind_udp(
+            &SocketAddr::V4(SocketAddrV4::new(
+                config.bind_ip.unwrap_or_else(|| ipv4!("0.0.0.0")),
+                port,
+            )),
+            &config.socket_options,
+        ).map_err(|e| bind_error(port, e))?;
+        sock.set_broadcast(true).map_err(DiscoveryError::Io)?;
+        let multicast = MulticastMembership::join(&sock, config).map_err(DiscoveryError::Io)?;
+        let port = sock.local_addr().map_err(DiscoveryError::Io)?.port();
+        let mut node = Self {
+            sock,
+            port,
+            core,
+            our_pk: *our_pk,
+            our_sk: our_sk.clone(),
+            config: config.clone(),
+            request,
+            requests: VecDeque::new(),
+            blocked: None,
+            multicast,
+            found: VecDeque::new(),
+            #[cfg(feature = "wire-tap")]
+            wire_tap: config.wire_tap.clone(),
+        };
+        node.shout()?;
+        Ok(node)
+    }
+
+    /// Returns the port node listens on and sends requests from.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Queues discovery requests to every discovery target, they are sent when node is polled.
+    pub fn shout(&mut self) -> Result<(), DiscoveryError> {
+        let targets = target_addrs(self.port, &self.config).map_err(DiscoveryError::Io)?;
+        let request = &self.request;
+        self.requests
+            .extend(targets.into_iter().map(|dest| Transmit {
+                dest,
+                data: request.clone(),
+                source: None,
+            }));
+        Ok(())
+    }
+
+    fn poll_recv(&mut self) -> io::Result<Async<()>> {
+        let sock = &mut self.sock;
+        let core = &mut self.core;
+        let found = &mut self.found;
+        let (our_pk, our_sk, config) = (&self.our_pk, &self.our_sk, &self.config);
+        #[cfg(feature = "wire-tap")]
+        let wire_tap = &self.wire_tap;
+        poll_recv_all(
+            |buf| sock.poll_recv_from(buf),
+            |sender_addr, buf| {
+                #[cfg(feature = "wire-tap")]
+                wire_tap::observe(wire_tap, Direction::Received, &sender_addr, buf);
+                match decrypt_response(buf, our_pk, our_sk) {
+                    Ok(resp) => {
+                        let peers: Vec<_> = resp
+                            .into_peers()
+                            .into_iter()
+                            .filter(|peer| is_acceptable_peer(peer, our_pk, config))
+                            .collect();
+                        if !peers.is_empty() {
+                            found.push_back(peers);
+                        }
+                    }
+                    // responses not meant for us are no reason to reject their sender
+                    Err(_) => match DiscoveryMsg::deserialize(buf) {
+                        Ok(DiscoveryMsg::Request(their_pk)) => {
+                            core.handle_request(sender_addr, their_pk)
+                        }
+                        _ => debug!("Ignoring unexpected datagram from {}", sender_addr),
+                    },
+                }
+            },
+            config.recv_batch_size,
+        )
+    }
+
+    fn poll_send(&mut self) {
+        let sock = &mut self.sock;
+        let core = &mut self.core;
+        let requests = &mut self.requests;
+        #[cfg(feature = "wire-tap")]
+        let wire_tap = &self.wire_tap;
+        poll_send_all(
+            |transmit| {
+                let bytes_sent = match sock.poll_send_to(&transmit.data, &transmit.dest)? {
+                    Async::Ready(bytes_sent) => bytes_sent,
+                    Async::NotReady => return Ok(Async::NotReady),
+                };
+                #[cfg(feature = "wire-tap")]
+                wire_tap::observe(
+                    wire_tap,
+                    Direction::Sent,
+                    &transmit.dest,
+                    &transmit.data[..bytes_sent],
+                );
+                let _ = is_fully_sent(transmit, bytes_sent);
+                Ok(Async::Ready(()))
+            },
+            || requests.pop_front().or_else(|| core.poll_transmit()),
+            &mut self.blocked,
+            self.config.send_timeout,
+            Instant::now(),
+        )
+    }
+}
+
+impl Stream for DiscoveryNode {
+    type Item = Vec<PeerInfo>;
+    type Error = DiscoveryError;
+
+    fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
+        let batch_full = self.poll_recv().map_err(DiscoveryError::Io)?.is_ready();
+        self.poll_send();
+        if batch_full {
+            task::current().notify();
+        }
+        match self.found.pop_front() {
+            Some(peers) => Ok(Async::Ready(Some(peers))),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl Drop for DiscoveryNode {
+    fn drop(&mut self) {
+        if let Some(ref multicast) = self.multicast {
+            multicast.leave(&self.sock);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest2::prelude::*;
+    use peer_discovery::DiscoveryResponse;
+    use tokio::runtime::current_thread::Runtime;
+    use tokio::timer::Delay;
+
+    /// Returns UDP port that is free at the moment.
+    fn free_port() -> u16 {
+        let sock = unwrap!(UdpSocket::bind(&addr!("0.0.0.0:0")));
+        unwrap!(sock.local_addr()).port()
+    }
+
+    #[test]
+    fn nodes_discover_each_other_while_answering_each_other() {
+        let mut evloop = unwrap!(Runtime::new());
+
+        let (port1, port2) = (free_port(), free_port());
+        let (pk1, sk1) = gen_encrypt_keypair();
+        let (pk2, sk2) = gen_encrypt_keypair();
+        let node1 = unwrap!(DiscoveryNode::new(
+            port1,
+            vec![addr!("192.168.1.100:1234")],
+            &pk1,
+            &sk1,
+            &DiscoveryConfig {
+                loopback_ports: vec![port2],
+                ..Default::default()
+            },
+        ));
+        let node2 = unwrap!(DiscoveryNode::new(
+            port2,
+            vec![addr!("192.168.1.101:1234")],
+            &pk2,
+            &sk2,
+            &DiscoveryConfig {
+                loopback_ports: vec![port1],
+                ..Default::default()
+            },
+        ));
+
+        let task = node1
+            .zip(node2)
+            .into_future()
+            .map(|(found, _nodes)| unwrap!(found))
+            .map_err(|(e, _nodes)| e)
+            .with_timeout(Duration::from_secs(10));
+
+        let (found_by_node1, found_by_node2) = unwrap!(unwrap!(evloop.block_on(task)));
+        assert_that!(
+            found_by_node1,
+            eq(vec![PeerInfo::new(addr!("192.168.1.101:1234"), pk2)])
+        );
+        assert_that!(
+            found_by_node2,
+            eq(vec![PeerInfo::new(addr!("192.168.1.100:1234"), pk1)])
+        );
+    }
+
+    #[test]
+    fn node_answers_peer_whose_response_it_could_not_decrypt() {
+        let mut evloop = unwrap!(Runtime::new());
+
+        let port = free_port();
+        let (our_pk, our_sk) = gen_encrypt_keypair();
+        let node = unwrap!(DiscoveryNode::new(
+            port,
+            vec![addr!("192.168.1.100:1234")],
+            &our_pk,
+            &our_sk,
+            &DiscoveryConfig {
+                loopback_ports: vec![free_port()],
+                ..Default::default()
+            },
+        ));
+        evloop.spawn(
+            node.for_each(|_found| Ok(()))
+                .map_err(|e| panic!("Discovery node failed: {:?}", e)),
+        );
+
+        let (their_pk, their_sk) = gen_encrypt_keypair();
+        let (other_pk, _other_sk) = gen_encrypt_keypair();
+        let peer = unwrap!(::std::net::UdpSocket::bind("127.0.0.1:0"));
+        unwrap!(peer.set_read_timeout(Some(Duration::from_secs(5))));
+        let node_addr = SocketAddr::V4(SocketAddrV4::new(ipv4!("127.0.0.1"), port));
+        let resp = DiscoveryResponse {
+            pub_key: their_pk,
+            addrs: vec![addr!("192.168.1.101:1234")],
+            app_data: Vec::new(),
+        };
+        let resp = unwrap!(DiscoveryMsg::encrypted_response(&other_pk, resp));
+        let req = unwrap!(DiscoveryMsg::serialized_request(their_pk));
+        for datagram in vec![resp; 10].iter().chain(Some(&req)) {
+            let _ = unwrap!(peer.send_to(datagram, node_addr));
+        }
+        unwrap!(evloop.block_on(Delay::new(Instant::now() + Duration::from_millis(200))));
+
+        let mut buf = [0; 2048];
+        let (bytes_read, _) = unwrap!(peer.recv_from(&mut buf));
+        let resp = unwrap!(decrypt_response(&buf[..bytes_read], &their_pk, &their_sk));
+        assert_that!(resp.pub_key, eq(our_pk));
+    }
+
+    #[test]
+    fn node_ignores_its_own_requests() {
+        let mut evloop = unwrap!(Runtime::new());
+
+        let port = free_port();
+        let (our_pk, our_sk) = gen_encrypt_keypair();
+        let node = unwrap!(DiscoveryNode::new(
+            port,
+            vec![addr!("192.168.1.100:1234")],
+            &our_pk,
+            &our_sk,
+            &DiscoveryConfig {
+                loopback_ports: vec![port],
+                ..Default::default()
+            },
+        ));
+
+        let task = node
+            .into_future()
+            .map(|(found, _node)| found)
+            .map_err(|(e, _node)| e)
+            .with_timeout(Duration::from_millis(500));
+
+        let found = unwrap!(evloop.block_on(task));
+        assert_that!(found, none());
+    }
+}
@@ -2,10 +2,17 @@
 //! LibreDrop is an alternative for Apple's AirDrop except aims to support all possible
 //! platforms. So it enables you to easilly exchange files between Linux <--> Android, iOS <-->
 //! Windows, etc.
+//!
+//! All futures and streams returned by this crate are `Send`, so they can be driven by either
+//! `tokio::runtime::current_thread::Runtime` or the default multi-threaded `tokio::runtime::Runtime`.
 
 extern crate future_utils;
 extern crate futures;
+#[cfg(feature = "futures03")]
+extern crate futures03;
 extern crate get_if_addrs;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+extern crate libc;
 extern crate maidsafe_utilities;
 extern crate safe_crypto;
 extern crate tokio;
@@ -13,7 +20,7 @@ extern crate tokio;
 extern crate serde_derive;
 extern crate bytes;
 extern crate serde;
-#[cfg(test)]
+extern crate socket2;
 #[macro_use]
 extern crate unwrap;
 #[macro_use]
@@ -26,8 +33,51 @@ extern crate void;
 #[macro_use]
 extern crate hamcrest2;
 
+mod beacon;
+mod compact_addrs;
+#[cfg(feature = "futures03")]
+pub mod compat;
+mod discovery_node;
 mod peer;
+mod peer_cache;
 mod peer_discovery;
+mod pktinfo;
+mod platform;
 mod priv_prelude;
+mod rate_limit;
+mod rejected_senders;
+#[cfg(feature = "route-hints")]
+pub mod route_hints;
+mod server_core;
+mod socket;
+mod subnet;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
+#[cfg(all(test, target_os = "linux"))]
+mod virtual_lan;
+#[cfg(feature = "wire-tap")]
+mod wire_tap;
 
-pub use peer_discovery::{discover_peers, shout_for_peers, DiscoveryError, DiscoveryServer};
+pub use beacon::{
+    announce_beacons, announce_beacons_with_config, listen_for_beacons,
+    listen_for_beacons_with_config, Beacon, BeaconListener,
+};
+pub use discovery_node::DiscoveryNode;
+pub use get_if_addrs::{IfAddr, Ifv4Addr, Ifv6Addr, Interface};
+pub use peer_cache::{CachedPeer, PeerCache};
+pub use peer_discovery::{
+    discover_all, discover_peers, discover_peers_cancellable,
+    discover_peers_cancellable_with_config, discover_peers_with_config, discovery_targets,
+    estimated_response_size, shout_for_peers, shout_for_peers_with_config,
+    shout_for_peers_with_rtt, CancelHandle, DiscoveredPeers, DiscoveryConfig, DiscoveryError,
+    DiscoveryServer, DiscoveryTarget, InterfaceSource, MergedPeer, DEFAULT_MAX_CONCURRENT_REQUESTS,
+    DEFAULT_MAX_INVALID_REQUESTS, DEFAULT_MAX_QUEUED_CLIENTS, DEFAULT_RECV_BATCH_SIZE,
+    DEFAULT_RESPONSE_TIMEOUT, DEFAULT_SEND_TIMEOUT, MAX_APP_DATA_LEN, SAFE_RESPONSE_SIZE,
+};
+pub use platform::Platform;
+pub use rate_limit::RateLimiter;
+pub use server_core::{ServerCore, Transmit};
+pub use socket::SocketOptions;
+pub use subnet::{Subnet, SubnetParseError};
+#[cfg(feature = "wire-tap")]
+pub use wire_tap::{Direction, WireTap};
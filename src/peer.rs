@@ -1,17 +1,194 @@
+use bincode::{self, Options};
+use peer_discovery::DiscoveryError;
 use priv_prelude::*;
+use safe_crypto;
+use std::fmt;
+
+/// Number of public key hash bytes used in peer fingerprint.
+pub const FINGERPRINT_BYTES: usize = 4;
 
 /// Information necessary to connect to peer.
-#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
+///
+/// Serialized peer info is meant to be persisted and passed around, so its format is kept stable:
+/// public key is always encoded as its raw 32 bytes, independently of how `safe_crypto` serializes
+/// it. `to_bytes()` gives canonical binary form.
+#[derive(PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
 pub struct PeerInfo {
     /// Peer public address.
     pub addr: SocketAddr,
     /// Peer public key.
+    #[serde(with = "key_bytes")]
     pub pub_key: PublicEncryptKey,
+    /// Opaque application data the peer advertised during discovery.
+    pub app_data: Vec<u8>,
 }
 
 impl PeerInfo {
     /// Constructs peer info.
     pub fn new(addr: SocketAddr, pub_key: PublicEncryptKey) -> Self {
-        Self { addr, pub_key }
+        Self {
+            addr,
+            pub_key,
+            app_data: Vec::new(),
+        }
+    }
+
+    /// Sets application data.
+    pub fn with_app_data(mut self, app_data: Vec<u8>) -> Self {
+        self.app_data = app_data;
+        self
+    }
+
+    /// Returns short hex encoded public key fingerprint. It's meant for logging and displaying
+    /// peers to users, not for peer identification.
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.pub_key)
+    }
+
+    /// Returns canonical binary encoding of peer info.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DiscoveryError> {
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .serialize(self)
+            .map_err(DiscoveryError::SerializeFailure)
+    }
+
+    /// Decodes peer info from bytes produced by `to_bytes()`. The input is not trusted: length
+    /// fields can't make it allocate more than the input size.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, DiscoveryError> {
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_limit(buf.len() as u64)
+            .deserialize_from(buf)
+            .map_err(DiscoveryError::SerializeFailure)
+    }
+}
+
+/// Serializes public key as raw bytes.
+mod key_bytes {
+    use super::*;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        key: &PublicEncryptKey,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        key.into_bytes().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<PublicEncryptKey, D::Error> {
+        <[u8; 32]>::deserialize(deserializer).map(PublicEncryptKey::from_bytes)
+    }
+}
+
+/// Returns short public key id: first bytes of its hash.
+pub fn key_id(pub_key: &PublicEncryptKey) -> [u8; FINGERPRINT_BYTES] {
+    let key_hash = safe_crypto::hash(&pub_key.into_bytes());
+    let mut id = [0; FINGERPRINT_BYTES];
+    id.copy_from_slice(&key_hash[..FINGERPRINT_BYTES]);
+    id
+}
+
+/// Returns short hex encoded public key fingerprint, see `PeerInfo::fingerprint()`.
+pub fn fingerprint(pub_key: &PublicEncryptKey) -> String {
+    key_id(pub_key)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Formats peer as `<fingerprint>@<address>` and keeps full public key out of the output.
+impl fmt::Display for PeerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}", self.fingerprint(), self.addr)
+    }
+}
+
+/// Like `Display`, shows public key fingerprint only, so that debug logs don't leak full keys.
+impl fmt::Debug for PeerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PeerInfo")
+            .field("addr", &self.addr)
+            .field("pub_key", &self.fingerprint())
+            .field("app_data", &self.app_data)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest2::prelude::*;
+
+    #[test]
+    fn fingerprint_is_short_hash_of_public_key() {
+        let pub_key = PublicEncryptKey::from_bytes([1; 32]);
+        let peer = PeerInfo::new(addr!("192.168.1.100:1234"), pub_key);
+
+        let key_hash = safe_crypto::hash(&[1; 32]);
+        let expected: String = key_hash[..4]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        assert_that!(peer.fingerprint(), eq(expected));
+    }
+
+    #[test]
+    fn to_bytes_and_from_bytes_round_trip() {
+        let (pub_key, _) = gen_encrypt_keypair();
+        let peer = PeerInfo::new(addr!("[fe80::1]:1234"), pub_key).with_app_data(vec![1, 2, 3]);
+
+        let bytes = unwrap!(peer.to_bytes());
+
+        assert_that!(unwrap!(PeerInfo::from_bytes(&bytes)), eq(peer));
+    }
+
+    #[test]
+    fn to_bytes_format_does_not_change() {
+        let pub_key = PublicEncryptKey::from_bytes([1; 32]);
+        let peer = PeerInfo::new(addr!("192.168.1.100:1234"), pub_key).with_app_data(vec![7, 8]);
+
+        let mut expected = vec![0, 0, 0, 0, 192, 168, 1, 100, 0xd2, 0x04];
+        expected.extend_from_slice(&[1; 32]);
+        expected.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 0, 7, 8]);
+        assert_that!(unwrap!(peer.to_bytes()), eq(expected));
+    }
+
+    #[test]
+    fn from_bytes_fails_on_truncated_input() {
+        let (pub_key, _) = gen_encrypt_keypair();
+        let bytes = unwrap!(PeerInfo::new(addr!("192.168.1.100:1234"), pub_key).to_bytes());
+
+        assert_that!(
+            PeerInfo::from_bytes(&bytes[..bytes.len() - 1]).is_err(),
+            is(true)
+        );
+    }
+
+    #[test]
+    fn display_shows_fingerprint_and_address() {
+        let (pub_key, _) = gen_encrypt_keypair();
+        let peer = PeerInfo::new(addr!("192.168.1.100:1234"), pub_key);
+
+        assert_that!(
+            format!("{}", peer),
+            eq(format!("{}@192.168.1.100:1234", peer.fingerprint()))
+        );
+    }
+
+    #[test]
+    fn debug_shows_fingerprint_instead_of_public_key() {
+        let pub_key = PublicEncryptKey::from_bytes([1; 32]);
+        let peer = PeerInfo::new(addr!("192.168.1.100:1234"), pub_key).with_app_data(vec![7]);
+
+        assert_that!(
+            format!("{:?}", peer),
+            eq(format!(
+                "PeerInfo {{ addr: 192.168.1.100:1234, pub_key: \"{}\", app_data: [7] }}",
+                peer.fingerprint()
+            ))
+        );
     }
 }
@@ -0,0 +1,175 @@
+//! Recently seen peers that survive process restarts, so applications can show known devices
+//! right away instead of an empty list while discovery is still running.
+
+use bincode::{self, Options};
+use peer_discovery::DiscoveryError;
+use priv_prelude::*;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Peer together with the time it was last seen.
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct CachedPeer {
+    pub peer: PeerInfo,
+    /// Seconds since Unix epoch.
+    last_seen: u64,
+}
+
+impl CachedPeer {
+    /// Returns the time peer was last seen at, with a precision of one second.
+    pub fn last_seen(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.last_seen)
+    }
+}
+
+/// Collection of discovered peers with their last seen time. Peers are identified by their public
+/// key and address, so the same peer discovered via multiple addresses takes multiple entries.
+///
+/// `save()` uses a compact binary format. It's only a helper for the common case: applications
+/// that want to store peers their own way can do that as `PeerInfo` is serializable.
+#[derive(Default, Debug, Clone)]
+pub struct PeerCache {
+    peers: Vec<CachedPeer>,
+}
+
+impl PeerCache {
+    /// Constructs empty peer cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that peer was just seen.
+    pub fn insert(&mut self, peer: PeerInfo) {
+        self.insert_seen_at(peer, SystemTime::now());
+    }
+
+    /// Records that peer was seen at a given time. Updates existing entry, if there's one.
+    pub fn insert_seen_at(&mut self, peer: PeerInfo, seen_at: SystemTime) {
+        let last_seen = unix_secs(seen_at);
+        let existing = self.peers.iter().position(|cached| {
+            cached.peer.pub_key == peer.pub_key && cached.peer.addr == peer.addr
+        });
+        if let Some(i) = existing {
+            let _ = self.peers.remove(i);
+        }
+        self.peers.push(CachedPeer { peer, last_seen });
+    }
+
+    /// Returns cached peers, the least recently inserted first.
+    pub fn peers(&self) -> &[CachedPeer] {
+        &self.peers
+    }
+
+    /// Writes cache to a given file. The file is overwritten, if it exists.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), DiscoveryError> {
+        let buf = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .serialize(&self.peers)
+            .map_err(DiscoveryError::SerializeFailure)?;
+        fs::write(path, buf).map_err(DiscoveryError::Io)
+    }
+
+    /// Reads cache from a given file. Peers not seen for longer than `ttl` are dropped. Fails with
+    /// `DiscoveryError::DeserializeFailed`, if the file is corrupt.
+    pub fn load<P: AsRef<Path>>(path: P, ttl: Duration) -> Result<Self, DiscoveryError> {
+        let buf = fs::read(path).map_err(DiscoveryError::Io)?;
+        let peers: Vec<CachedPeer> = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_limit(buf.len() as u64)
+            .deserialize_from(&buf[..])
+            .map_err(DiscoveryError::DeserializeFailed)?;
+        let oldest_allowed = unix_secs(SystemTime::now()).saturating_sub(ttl.as_secs());
+        Ok(Self {
+            peers: peers
+                .into_iter()
+                .filter(|cached| cached.last_seen >= oldest_allowed)
+                .collect(),
+        })
+    }
+}
+
+/// Returns seconds since Unix epoch. Times before the epoch are treated as the epoch itself.
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest2::prelude::*;
+    use std::env;
+    use std::path::PathBuf;
+    use std::process;
+
+    /// Returns path to a temporary file unique to the test.
+    fn temp_file(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("libredrop-peer-cache-{}-{}", process::id(), name))
+    }
+
+    fn peer() -> PeerInfo {
+        let (pub_key, _) = gen_encrypt_keypair();
+        PeerInfo::new(addr!("192.168.1.100:1234"), pub_key)
+    }
+
+    #[test]
+    fn insert_updates_already_cached_peer() {
+        let mut cache = PeerCache::new();
+        let peer = peer();
+
+        cache.insert_seen_at(peer.clone(), UNIX_EPOCH + Duration::from_secs(10));
+        cache.insert_seen_at(peer.clone(), UNIX_EPOCH + Duration::from_secs(20));
+
+        assert_that!(cache.peers().len(), eq(1));
+        assert_that!(
+            cache.peers()[0].last_seen(),
+            eq(UNIX_EPOCH + Duration::from_secs(20))
+        );
+    }
+
+    #[test]
+    fn saved_cache_can_be_loaded() {
+        let path = temp_file("roundtrip");
+        let mut cache = PeerCache::new();
+        cache.insert(peer());
+        cache.insert(peer().with_app_data(b"Laptop".to_vec()));
+
+        unwrap!(cache.save(&path));
+        let loaded = unwrap!(PeerCache::load(&path, Duration::from_secs(3600)));
+        let _ = fs::remove_file(&path);
+
+        assert_that!(loaded.peers(), eq(cache.peers()));
+    }
+
+    #[test]
+    fn load_drops_stale_peers() {
+        let path = temp_file("stale");
+        let mut cache = PeerCache::new();
+        let fresh_peer = peer();
+        cache.insert_seen_at(peer(), SystemTime::now() - Duration::from_secs(7200));
+        cache.insert(fresh_peer.clone());
+
+        unwrap!(cache.save(&path));
+        let loaded = unwrap!(PeerCache::load(&path, Duration::from_secs(3600)));
+        let _ = fs::remove_file(&path);
+
+        assert_that!(loaded.peers().len(), eq(1));
+        assert_that!(&loaded.peers()[0].peer, eq(&fresh_peer));
+    }
+
+    #[test]
+    fn load_fails_on_corrupt_file() {
+        let path = temp_file("corrupt");
+        unwrap!(fs::write(&path, [0xff; 16]));
+
+        let res = PeerCache::load(&path, Duration::from_secs(3600));
+        let _ = fs::remove_file(&path);
+
+        match res {
+            Err(DiscoveryError::DeserializeFailed(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
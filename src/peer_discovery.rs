@@ -1,38 +1,347 @@
-use bincode;
+use beacon::BeaconMsg;
+use bincode::{self, Options};
+use compact_addrs;
 use futures::stream;
-use get_if_addrs::{get_if_addrs, IfAddr};
+use futures::sync::mpsc;
+use futures::task::{self, AtomicTask};
+use get_if_addrs::{get_if_addrs, IfAddr, Interface};
+use peer;
+use pktinfo;
 use priv_prelude::*;
+use rate_limit::RateLimiter;
+use server_core::{ServerCore, Transmit};
+use socket::{self, SocketOptions};
+use std::cmp;
+use std::fmt;
 use std::io;
-use std::net::SocketAddrV4;
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4, TcpListener};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use subnet::{self, Subnet};
 use tokio::net::UdpSocket;
+use tokio::timer::Delay;
+#[cfg(feature = "wire-tap")]
+use wire_tap::{self, Direction, WireTap};
 
 /// Tries given expression. Returns boxed stream error on failure.
 macro_rules! try_bstream {
     ($e:expr) => {
         match $e {
             Ok(t) => t,
-            Err(e) => return stream::iter_result(vec![Err(e)]).into_boxed(),
+            Err(e) => return stream::iter_result(vec![Err(e)]).into_send_boxed(),
         }
     };
 }
 
+/// Maximum size of discovery message. Messages are never bigger than a single datagram and
+/// incoming messages are not allowed to allocate more than that when deserialized.
+const MAX_MSG_SIZE: usize = 65000;
+
+/// Discovery responses bigger than this might get fragmented or silently dropped on some networks.
+/// 1232 bytes is the biggest UDP payload that fits into the minimum IPv6 MTU (1280 bytes) and is a
+/// commonly used safe limit for IPv4 as well. Message header and encryption add 51 bytes to the
+/// serialized response, address list - 2 bytes and every IPv4 address takes 6 bytes, IPv6 - 18
+/// bytes.
+pub const SAFE_RESPONSE_SIZE: usize = 1232;
+
+/// Number of bytes anonymous encryption adds to the plaintext: ephemeral public key and MAC.
+const ENCRYPTION_OVERHEAD: usize = 48;
+
+/// Every discovery message starts with these bytes followed by protocol version, so that
+/// messages of incompatible versions are told apart from garbage.
+const MSG_MAGIC: [u8; 2] = *b"LD";
+
+/// Version of discovery messages format. Bump it on every incompatible change of `DiscoveryMsg`.
+const PROTOCOL_VERSION: u8 = 1;
+
+/// Size of magic bytes and protocol version prepended to every message.
+const MSG_HEADER_SIZE: usize = 3;
+
+/// How long discovery waits for responses to a single request by default.
+pub const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How many discovery requests are in flight at once by default.
+pub const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 16;
+
+/// How long discovery server keeps a response the socket is not ready to send by default. By
+/// then the requester has most likely stopped waiting for it.
+pub const DEFAULT_SEND_TIMEOUT: Duration = DEFAULT_RESPONSE_TIMEOUT;
+
+/// How many clients discovery server queues responses for by default.
+pub const DEFAULT_MAX_QUEUED_CLIENTS: usize = 256;
+
+/// How many requests discovery server receives at once by default, before sending responses.
+pub const DEFAULT_RECV_BATCH_SIZE: usize = 64;
+
+/// How many invalid datagrams discovery server accepts from a single host by default, before it
+/// ignores the host for a while.
+pub const DEFAULT_MAX_INVALID_REQUESTS: u32 = 5;
+
+/// Peer discovery configuration.
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    /// Don't filter out our own responses. Useful when testing on a single host.
+    pub include_self: bool,
+    /// When not empty, discovery only broadcasts on these subnets and ignores peer addresses
+    /// outside of them.
+    pub allowed_subnets: Vec<Subnet>,
+    /// Application data our discovery server attaches to responses. Must not be longer than
+    /// `MAX_APP_DATA_LEN`.
+    pub app_data: Vec<u8>,
+    /// Options for all sockets used by discovery.
+    pub socket_options: SocketOptions,
+    /// Local IPv4 address discovery server listens on, `0.0.0.0` by default. Meant for servers
+    /// that should only be reachable via a single address, e.g. loopback. On Linux a socket
+    /// bound to a unicast address doesn't receive broadcast datagrams, so such server won't
+    /// answer LAN wide discovery requests, only the ones sent directly to that address.
+    ///
+    /// `DiscoveryServer` bound to `0.0.0.0` sends every response from the local address the
+    /// request arrived on, so that on interfaces with multiple addresses requesters get an answer
+    /// from the address they can route back to. That's done with `IP_PKTINFO` on Linux and
+    /// Android, elsewhere OS picks the source address.
+    pub bind_ip: Option<Ipv4Addr>,
+    /// Observes every datagram discovery sends or receives.
+    #[cfg(feature = "wire-tap")]
+    pub wire_tap: Option<WireTap>,
+    /// Maximum number of discovery requests in flight at once. Every broadcast address takes one
+    /// socket, so this bounds resource usage on hosts with lots of interfaces, the rest are
+    /// queued. Values below 1 are treated as 1.
+    pub max_concurrent_requests: usize,
+    /// How long responses to every request are collected for. Many peers can answer the same
+    /// broadcast request, so discovery keeps receiving until this time is up.
+    pub response_timeout: Duration,
+    /// How long discovery server keeps trying to send a response while socket is not ready,
+    /// before giving up on that response.
+    pub send_timeout: Duration,
+    /// How many invalid datagrams discovery server accepts from a single IP before ignoring it
+    /// for 30 seconds. A single bad datagram is not enough: source addresses can be spoofed and
+    /// hosts behind the same NAT share one. Counting starts over once the host stays quiet for
+    /// 30 seconds. Values below 1 are treated as 1.
+    pub max_invalid_requests: u32,
+    /// IPv4 multicast group, e.g. `239.255.42.99`, discovery uses in addition to broadcast: server
+    /// joins it on every IPv4 interface within `allowed_subnets` and requests are sent to it as
+    /// well. Unlike broadcast, multicast can span routed network segments if
+    /// `socket_options.multicast_ttl` is big enough.
+    pub multicast_group: Option<Ipv4Addr>,
+    /// Maximum number of clients discovery server keeps waiting for response. When the queue is
+    /// full, new requests are dropped. Every queued client takes 84 bytes.
+    pub max_queued_clients: usize,
+    /// Loopback mode for running multiple instances on a single host, e.g. in demos and tests:
+    /// when not empty, requests are sent only to `127.0.0.1` on these ports, no broadcast or
+    /// multicast is used. Instances must use distinct discovery ports, since two servers can't
+    /// listen on the same one. `::1` is not supported, discovery servers only listen on IPv4.
+    pub loopback_ports: Vec<u16>,
+    /// Network interfaces requests are broadcast on, the ones reported by operating system by
+    /// default.
+    pub interfaces: InterfaceSource,
+    /// Maximum number of requests discovery server receives in a row before it sends queued
+    /// responses, so that a flood of requests can't hold up the responses. Values below 1 are
+    /// treated as 1.
+    pub recv_batch_size: usize,
+    /// Check that we can actually listen on each of our addresses before advertising them and
+    /// skip the ones we can't, e.g. addresses of interfaces that went away. Peers would fail to
+    /// connect to such addresses anyway. Off by default, since probing takes time.
+    pub probe_our_addrs: bool,
+    /// Caps discovery request rate. Share the limiter between configs to cap the total rate of
+    /// all discoveries using them. No limit by default.
+    pub rate_limiter: Option<RateLimiter>,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            include_self: false,
+            allowed_subnets: Vec::new(),
+            app_data: Vec::new(),
+            socket_options: SocketOptions::default(),
+            bind_ip: None,
+            #[cfg(feature = "wire-tap")]
+            wire_tap: None,
+            max_concurrent_requests: DEFAULT_MAX_CONCURRENT_REQUESTS,
+            response_timeout: DEFAULT_RESPONSE_TIMEOUT,
+            send_timeout: DEFAULT_SEND_TIMEOUT,
+            max_invalid_requests: DEFAULT_MAX_INVALID_REQUESTS,
+            multicast_group: None,
+            max_queued_clients: DEFAULT_MAX_QUEUED_CLIENTS,
+            loopback_ports: Vec::new(),
+            interfaces: InterfaceSource::default(),
+            recv_batch_size: DEFAULT_RECV_BATCH_SIZE,
+            probe_our_addrs: false,
+            rate_limiter: None,
+        }
+    }
+}
+
+type ListInterfaces = dyn Fn() -> io::Result<Vec<Interface>> + Send + Sync;
+
+/// Lists network interfaces discovery broadcasts on. Useful where system enumeration gives
+/// the wrong interfaces, e.g. in some containers, and for tests.
+#[derive(Clone)]
+pub struct InterfaceSource(Arc<ListInterfaces>);
+
+impl InterfaceSource {
+    /// Wraps function that lists interfaces.
+    pub fn new<F>(list_interfaces: F) -> Self
+    where
+        F: Fn() -> io::Result<Vec<Interface>> + Send + Sync + 'static,
+    {
+        InterfaceSource(Arc::new(list_interfaces))
+    }
+
+    /// Lists interfaces reported by operating system.
+    pub fn system() -> Self {
+        Self::new(get_if_addrs)
+    }
+
+    fn list(&self) -> io::Result<Vec<Interface>> {
+        (self.0)()
+    }
+}
+
+impl Default for InterfaceSource {
+    fn default() -> Self {
+        Self::system()
+    }
+}
+
+impl fmt::Debug for InterfaceSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "InterfaceSource")
+    }
+}
+
 /// Search for peers on LAN and at the same time handle other discovery requests on a given port.
 /// This functions wraps `DiscoveryServer` and `shout_for_peers()` and probably will be used
 /// the most for its easiest API.
+///
+/// Discovery server lives exactly as long as the search: once the stream ends or fails, the
+/// server is stopped and its socket closed, even if the stream itself is kept around. Prefer
+/// this over driving `DiscoveryServer` next to `shout_for_peers()` with `while_driving()`, which
+/// hands the still running server back to the caller when the search completes.
 pub fn discover_peers(
     port: u16,
     our_addrs: Vec<SocketAddr>,
     our_pk: &PublicEncryptKey,
     our_sk: &SecretEncryptKey,
-) -> Result<impl Stream<Item = Vec<PeerInfo>, Error = DiscoveryError>, DiscoveryError> {
-    DiscoverPeers::new(port, our_addrs, our_pk, our_sk)
+) -> Result<impl Stream<Item = Vec<PeerInfo>, Error = DiscoveryError> + Send, DiscoveryError> {
+    discover_peers_with_config(port, our_addrs, our_pk, our_sk, &DiscoveryConfig::default())
+}
+
+/// Same as `discover_peers()` but with custom configuration.
+pub fn discover_peers_with_config(
+    port: u16,
+    our_addrs: Vec<SocketAddr>,
+    our_pk: &PublicEncryptKey,
+    our_sk: &SecretEncryptKey,
+    config: &DiscoveryConfig,
+) -> Result<impl Stream<Item = Vec<PeerInfo>, Error = DiscoveryError> + Send, DiscoveryError> {
+    DiscoverPeers::new(port, our_addrs, our_pk, our_sk, config)
+}
+
+/// Same as `discover_peers()` but also returns a handle that can stop peer discovery at any time.
+/// Once cancelled, the stream ends and all discovery sockets are closed as soon as the task
+/// driving the stream is woken up, even if the stream itself is kept around.
+pub fn discover_peers_cancellable(
+    port: u16,
+    our_addrs: Vec<SocketAddr>,
+    our_pk: &PublicEncryptKey,
+    our_sk: &SecretEncryptKey,
+) -> Result<
+    (
+        impl Stream<Item = Vec<PeerInfo>, Error = DiscoveryError> + Send,
+        CancelHandle,
+    ),
+    DiscoveryError,
+> {
+    discover_peers_cancellable_with_config(
+        port,
+        our_addrs,
+        our_pk,
+        our_sk,
+        &DiscoveryConfig::default(),
+    )
+}
+
+/// Same as `discover_peers_cancellable()` but with custom configuration.
+pub fn discover_peers_cancellable_with_config(
+    port: u16,
+    our_addrs: Vec<SocketAddr>,
+    our_pk: &PublicEncryptKey,
+    our_sk: &SecretEncryptKey,
+    config: &DiscoveryConfig,
+) -> Result<
+    (
+        impl Stream<Item = Vec<PeerInfo>, Error = DiscoveryError> + Send,
+        CancelHandle,
+    ),
+    DiscoveryError,
+> {
+    let discovery = DiscoverPeers::new(port, our_addrs, our_pk, our_sk, config)?;
+    let state = Arc::new(CancelState {
+        cancelled: AtomicBool::new(false),
+        task: AtomicTask::new(),
+    });
+    let handle = CancelHandle {
+        state: state.clone(),
+    };
+    let peers = CancellableDiscoverPeers {
+        discovery: Some(discovery),
+        state,
+    };
+    Ok((peers, handle))
+}
+
+/// Stops peer discovery started with `discover_peers_cancellable()`. The handle is `Send`, so
+/// discovery can be cancelled from a different thread than the one driving it.
+pub struct CancelHandle {
+    state: Arc<CancelState>,
+}
+
+impl CancelHandle {
+    /// Terminates peer discovery stream and releases its sockets. Calling it more than once has
+    /// no effect.
+    pub fn cancel(&self) {
+        if !self.state.cancelled.swap(true, Ordering::SeqCst) {
+            info!("Peer discovery cancelled");
+            self.state.task.notify();
+        }
+    }
+}
+
+struct CancelState {
+    cancelled: AtomicBool,
+    // task polling the discovery stream, notified on cancel
+    task: AtomicTask,
+}
+
+struct CancellableDiscoverPeers {
+    // `None` once discovery was cancelled
+    discovery: Option<DiscoverPeers>,
+    state: Arc<CancelState>,
+}
+
+impl Stream for CancellableDiscoverPeers {
+    type Item = Vec<PeerInfo>;
+    type Error = DiscoveryError;
+
+    fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
+        self.state.task.register();
+        if self.state.cancelled.load(Ordering::SeqCst) {
+            self.discovery = None;
+        }
+        match self.discovery {
+            Some(ref mut discovery) => discovery.poll(),
+            None => Ok(Async::Ready(None)),
+        }
+    }
 }
 
 struct DiscoverPeers {
-    // future that never resolves
-    server: DiscoveryServer,
+    // future that never resolves, `None` once discovery is over
+    server: Option<DiscoveryServer>,
     // stream of peer discovery requests awaiting for response
-    send_reqs: BoxStream<Vec<PeerInfo>, DiscoveryError>,
+    send_reqs: BoxSendStream<Vec<PeerInfo>, DiscoveryError>,
 }
 
 impl DiscoverPeers {
@@ -41,10 +350,23 @@ impl DiscoverPeers {
         our_addrs: Vec<SocketAddr>,
         our_pk: &PublicEncryptKey,
         our_sk: &SecretEncryptKey,
+        config: &DiscoveryConfig,
     ) -> Result<Self, DiscoveryError> {
-        let server = DiscoveryServer::new(port, our_addrs, our_pk)?;
-        let send_reqs = shout_for_peers(port, our_pk, our_sk).into_boxed();
-        Ok(Self { server, send_reqs })
+        let server = DiscoveryServer::with_config(port, our_addrs, our_pk, config)?;
+        let send_reqs = shout_for_peers_with_config(port, our_pk, our_sk, config).into_send_boxed();
+        Ok(Self {
+            server: Some(server),
+            send_reqs,
+        })
+    }
+}
+
+impl DiscoverPeers {
+    fn poll_both(&mut self) -> Result<Async<Option<Vec<PeerInfo>>>, DiscoveryError> {
+        if let Some(ref mut server) = self.server {
+            let _ = server.poll()?;
+        }
+        self.send_reqs.poll()
     }
 }
 
@@ -54,8 +376,12 @@ impl Stream for DiscoverPeers {
 
     /// Send peer discovery messages while also driving the discovery server.
     fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
-        self.server.poll()?;
-        self.send_reqs.poll()
+        let res = self.poll_both();
+        match res {
+            Ok(Async::Ready(None)) | Err(_) => self.server = None,
+            _ => (),
+        }
+        res
     }
 }
 
@@ -65,22 +391,167 @@ impl Stream for DiscoverPeers {
 pub enum DiscoveryError {
     Io(io::Error),
     SerializeFailure(bincode::Error),
+    /// Response was decoded but it's not a discovery response.
     InvalidResponse,
+    /// Response could not be decrypted with our keys: it was not meant for us or it's corrupted.
+    DecryptFailed(EncryptionError),
+    /// Response was decrypted but could not be deserialized, or peer cache file is corrupt.
+    DeserializeFailed(bincode::Error),
+    /// Message comes from a peer running different discovery protocol version. Holds that
+    /// version, 0 for peers that predate versioned messages.
+    UnsupportedVersion(u8),
+    /// Application data exceeds `MAX_APP_DATA_LEN`. Holds the rejected data length.
+    AppDataTooLong(usize),
+    /// Discovery server was given no addresses to respond with.
+    NoAddrs,
+    /// Port is already taken by another socket.
+    PortInUse(u16),
+    /// Binding to the port requires elevated privileges, e.g. ports below 1024 on most Unix
+    /// systems.
+    PortRequiresPrivileges(u16),
+}
+
+/// Maps error of binding to a given port, so that the common failures could be told apart.
+pub(crate) fn bind_error(port: u16, e: io::Error) -> DiscoveryError {
+    match e.kind() {
+        io::ErrorKind::AddrInUse => DiscoveryError::PortInUse(port),
+        io::ErrorKind::PermissionDenied => DiscoveryError::PortRequiresPrivileges(port),
+        _ => DiscoveryError::Io(e),
+    }
+}
+
+/// Maximum size of application data in bytes that can be attached to discovery responses.
+/// It's kept small so that responses would fit into a single datagram.
+pub const MAX_APP_DATA_LEN: usize = 512;
+
+/// Returns the size of encrypted discovery response advertising given addresses and application
+/// data. Compare it with `SAFE_RESPONSE_SIZE` to check at configuration time whether the response
+/// fits into a datagram: otherwise discovery server trims it.
+pub fn estimated_response_size(our_addrs: &[SocketAddr], app_data: &[u8]) -> usize {
+    DiscoveryResponse {
+        pub_key: PublicEncryptKey::from_bytes([0; 32]),
+        addrs: our_addrs.to_vec(),
+        app_data: app_data.to_vec(),
+    }.encrypted_size()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-enum DiscoveryMsg {
+pub(crate) enum DiscoveryMsg {
     /// Request has sender's public key which should be used to encrypt response.
     Request(PublicEncryptKey),
     /// Addresses that the peer is accessible with.
-    Response(Vec<PeerInfo>),
+    Response(DiscoveryResponse),
+    /// Periodic announcement of a peer, see `announce_beacons()`.
+    Beacon(BeaconMsg),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct DiscoveryResponse {
+    pub pub_key: PublicEncryptKey,
+    #[serde(with = "compact_addrs")]
+    pub addrs: Vec<SocketAddr>,
+    /// Opaque application data.
+    pub app_data: Vec<u8>,
+}
+
+impl DiscoveryResponse {
+    /// Constructs peer info for every advertised address.
+    pub fn into_peers(self) -> Vec<PeerInfo> {
+        let (pub_key, app_data) = (self.pub_key, self.app_data);
+        self.addrs
+            .into_iter()
+            .map(|addr| PeerInfo::new(addr, pub_key).with_app_data(app_data.clone()))
+            .collect()
+    }
+
+    /// Returns the size of serialized and encrypted response.
+    fn encrypted_size(&self) -> usize {
+        let msg = DiscoveryMsg::Response(self.clone());
+        bincode::serialized_size(&msg)
+            .map(|size| MSG_HEADER_SIZE + size as usize + ENCRYPTION_OVERHEAD)
+            .unwrap_or(::std::usize::MAX)
+    }
+
+    /// Makes sure encrypted response fits into `SAFE_RESPONSE_SIZE`. Application data is dropped
+    /// first and then trailing addresses until the response is small enough.
+    pub fn fit_into_datagram(mut self) -> Self {
+        if self.encrypted_size() > SAFE_RESPONSE_SIZE && !self.app_data.is_empty() {
+            warn!(
+                "Discovery response exceeds {} bytes, dropping application data",
+                SAFE_RESPONSE_SIZE
+            );
+            self.app_data.clear();
+        }
+        let addrs_count = self.addrs.len();
+        while self.encrypted_size() > SAFE_RESPONSE_SIZE && !self.addrs.is_empty() {
+            let _ = self.addrs.pop();
+        }
+        if self.addrs.len() < addrs_count {
+            warn!(
+                "Discovery response exceeds {} bytes, advertising only {} of {} addresses",
+                SAFE_RESPONSE_SIZE,
+                self.addrs.len(),
+                addrs_count
+            );
+        }
+        self
+    }
 }
 
 impl DiscoveryMsg {
+    /// Serializes message prefixed with magic bytes and our protocol version.
+    pub fn serialize(&self) -> Result<Vec<u8>, DiscoveryError> {
+        let mut buf = MSG_MAGIC.to_vec();
+        buf.push(PROTOCOL_VERSION);
+        bincode::serialize_into(&mut buf, self).map_err(DiscoveryError::SerializeFailure)?;
+        Ok(buf)
+    }
+
     /// Returns serialized but not encrypted peer discovery request.
-    fn serialized_request(pk: PublicEncryptKey) -> Result<Vec<u8>, DiscoveryError> {
-        let msg = DiscoveryMsg::Request(pk);
-        bincode::serialize(&msg).map_err(DiscoveryError::SerializeFailure)
+    pub fn serialized_request(pk: PublicEncryptKey) -> Result<Vec<u8>, DiscoveryError> {
+        DiscoveryMsg::Request(pk).serialize()
+    }
+
+    /// Deserializes untrusted message. Length fields embedded into the message can't make
+    /// deserialization allocate more than `MAX_MSG_SIZE` bytes. Messages of other protocol
+    /// versions fail with `DiscoveryError::UnsupportedVersion`.
+    pub fn deserialize(buf: &[u8]) -> Result<Self, DiscoveryError> {
+        if buf.len() < MSG_HEADER_SIZE || buf[..MSG_MAGIC.len()] != MSG_MAGIC {
+            return Err(match Self::legacy_variant(buf) {
+                Some(_) => DiscoveryError::UnsupportedVersion(0),
+                None => DiscoveryError::SerializeFailure(Box::new(bincode::ErrorKind::Custom(
+                    "Not a discovery message".to_owned(),
+                ))),
+            });
+        }
+        match buf[MSG_MAGIC.len()] {
+            PROTOCOL_VERSION => (),
+            version => return Err(DiscoveryError::UnsupportedVersion(version)),
+        }
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_limit(MAX_MSG_SIZE as u64)
+            // deserializing from slice ignores the limit, hence use reader API
+            .deserialize_from(&buf[MSG_HEADER_SIZE..])
+            .map_err(DiscoveryError::SerializeFailure)
+    }
+
+    /// Messages before protocol version was introduced have no header and start with
+    /// little endian `u32` variant index: 0 for requests, 1 for responses.
+    fn legacy_variant(buf: &[u8]) -> Option<u8> {
+        match buf.get(..4) {
+            Some(&[variant, 0, 0, 0]) if variant <= 1 => Some(variant),
+            _ => None,
+        }
+    }
+
+    /// Returns serialized response encrypted with their public key.
+    pub fn encrypted_response(
+        their_pk: &PublicEncryptKey,
+        resp: DiscoveryResponse,
+    ) -> Result<Vec<u8>, DiscoveryError> {
+        let plaintext = DiscoveryMsg::Response(resp).serialize()?;
+        Ok(their_pk.anonymously_encrypt_bytes(&plaintext))
     }
 }
 
@@ -88,90 +559,273 @@ impl DiscoveryMsg {
 /// we're listening on so other peers could connect to us.
 pub struct DiscoveryServer {
     listener: UdpSocket,
-    /// Addresses peer discovery will respond with.
-    our_addrs: Vec<SocketAddr>,
-    our_pk: PublicEncryptKey,
-    port: u16,
-    /// Clients still waiting for response.
-    clients: Vec<(SocketAddr, PublicEncryptKey)>,
+    local_addr: SocketAddr,
+    core: ServerCore,
+    blocked: Option<BlockedTransmit>,
+    multicast: Option<MulticastMembership>,
+    send_timeout: Duration,
+    recv_batch_size: usize,
+    probe_our_addrs: bool,
+    /// New sets of addresses to respond with.
+    addr_updates: Option<mpsc::Receiver<Vec<SocketAddr>>>,
+    #[cfg(feature = "wire-tap")]
+    wire_tap: Option<WireTap>,
 }
 
 impl DiscoveryServer {
-    /// Constructs new peer discovery server that listens for requests on a given port.
+    /// Constructs new peer discovery server that listens for requests on a given port. Port 0
+    /// makes OS pick a free port, find out which one with `port()`. Fails, if `our_addrs` is
+    /// empty, since such responses would be useless to peers, or if the port can't be bound.
+    ///
+    /// Link-local IPv6 addresses are not advertised: they are only usable with a scope id, which
+    /// is a local interface index and means nothing on the peer's host. Advertise global or
+    /// unique local IPv6 addresses instead.
     pub fn new(
         port: u16,
         our_addrs: Vec<SocketAddr>,
         our_pk: &PublicEncryptKey,
     ) -> Result<Self, DiscoveryError> {
-        let listener = UdpSocket::bind(&SocketAddr::V4(SocketAddrV4::new(ipv4!("0.0.0.0"), port)))
-            .map_err(DiscoveryError::Io)?;
-        let port = listener.local_addr().map_err(DiscoveryError::Io)?.port();
+        Self::with_app_data(port, our_addrs, our_pk, Vec::new())
+    }
+
+    /// Constructs new peer discovery server that also sends given application data with every
+    /// response. Fails, if `app_data` is longer than `MAX_APP_DATA_LEN`.
+    pub fn with_app_data(
+        port: u16,
+        our_addrs: Vec<SocketAddr>,
+        our_pk: &PublicEncryptKey,
+        app_data: Vec<u8>,
+    ) -> Result<Self, DiscoveryError> {
+        let config = DiscoveryConfig {
+            app_data,
+            ..Default::default()
+        };
+        Self::with_config(port, our_addrs, our_pk, &config)
+    }
+
+    /// Constructs new peer discovery server with custom configuration. Server responds to
+    /// ourselves only if `config.include_self` is set.
+    pub fn with_config(
+        port: u16,
+        our_addrs: Vec<SocketAddr>,
+        our_pk: &PublicEncryptKey,
+        config: &DiscoveryConfig,
+    ) -> Result<Self, DiscoveryError> {
+        let our_addrs = if config.probe_our_addrs {
+            probe_addrs(our_addrs)
+        } else {
+            our_addrs
+        };
+        let core = ServerCore::new(our_addrs, our_pk, config)?;
+        let listener = socket::bind_udp(
+            &SocketAddr::V4(SocketAddrV4::new(
+                config.bind_ip.unwrap_or_else(|| ipv4!("0.0.0.0")),
+                port,
+            )),
+            &config.socket_options,
+        ).map_err(|e| bind_error(port, e))?;
+        if config.bind_ip.is_none() {
+            pktinfo::enable(&listener).map_err(DiscoveryError::Io)?;
+        }
+        let multicast = MulticastMembership::join(&listener, config).map_err(DiscoveryError::Io)?;
+        let local_addr = listener.local_addr().map_err(DiscoveryError::Io)?;
         Ok(Self {
             listener,
-            our_addrs,
-            our_pk: *our_pk,
-            port,
-            clients: Vec::new(),
+            local_addr,
+            core,
+            blocked: None,
+            multicast,
+            send_timeout: config.send_timeout,
+            recv_batch_size: config.recv_batch_size,
+            probe_our_addrs: config.probe_our_addrs,
+            addr_updates: None,
+            #[cfg(feature = "wire-tap")]
+            wire_tap: config.wire_tap.clone(),
         })
     }
 
+    /// Makes server respond with address sets received from a given channel, e.g. the ones from
+    /// network change watcher, without rebinding the socket. Updates are applied when server is
+    /// polled, the latest one wins. Addresses are filtered the same way as on construction, while
+    /// there are none left, server ignores requests. Replaces channel given before, if any. When
+    /// all senders are dropped, server keeps responding with the last addresses it got.
+    pub fn set_addr_updates(&mut self, updates: mpsc::Receiver<Vec<SocketAddr>>) {
+        self.addr_updates = Some(updates);
+    }
+
     /// Returns server port.
     pub fn port(&self) -> u16 {
-        self.port
+        self.local_addr.port()
     }
 
-    fn poll_requests(&mut self) -> io::Result<()> {
-        let mut buf = vec![0u8; 65000];
-        loop {
-            match self.listener.poll_recv_from(&mut buf)? {
-                Async::Ready((bytes_read, sender_addr)) => {
-                    self.on_packet_recv(&buf[..bytes_read], sender_addr);
+    /// Returns local addresses server listens on. Currently that's always a single IPv4 address:
+    /// `0.0.0.0` or `DiscoveryConfig::bind_ip`, if it was set.
+    pub fn bound_addrs(&self) -> Vec<SocketAddr> {
+        vec![self.local_addr]
+    }
+
+    /// By default server ignores requests signed with its own public key. This allows to change
+    /// that.
+    pub fn set_respond_to_self(&mut self, respond: bool) {
+        self.core.set_respond_to_self(respond);
+    }
+
+    /// Returns how many requests were dropped so far because too many clients were already
+    /// waiting for response. See `DiscoveryConfig::max_queued_clients`.
+    pub fn dropped_requests(&self) -> u64 {
+        self.core.dropped_requests()
+    }
+
+    fn poll_addr_updates(&mut self) {
+        let mut latest = None;
+        let mut ended = false;
+        if let Some(ref mut updates) = self.addr_updates {
+            loop {
+                match updates.poll() {
+                    Ok(Async::Ready(Some(our_addrs))) => latest = Some(our_addrs),
+                    Ok(Async::Ready(None)) | Err(()) => {
+                        ended = true;
+                        break;
+                    }
+                    Ok(Async::NotReady) => break,
                 }
-                Async::NotReady => return Ok(()),
             }
         }
+        if ended {
+            self.addr_updates = None;
+        }
+        if let Some(our_addrs) = latest {
+            let our_addrs = if self.probe_our_addrs {
+                probe_addrs(our_addrs)
+            } else {
+                our_addrs
+            };
+            self.core.set_our_addrs(our_addrs);
+        }
     }
 
-    fn on_packet_recv(&mut self, buf: &[u8], sender_addr: SocketAddr) {
-        match bincode::deserialize(buf) {
-            Ok(DiscoveryMsg::Request(their_pk)) => {
-                if their_pk != self.our_pk {
-                    // don't respond to ourselves
-                    self.clients.push((sender_addr, their_pk))
+    fn poll_requests(&mut self) -> io::Result<Async<()>> {
+        let listener = &mut self.listener;
+        let core = &mut self.core;
+        #[cfg(feature = "wire-tap")]
+        let wire_tap = &self.wire_tap;
+        poll_recv_all(
+            |buf| match pktinfo::poll_recv_from(listener, buf)? {
+                Async::Ready((bytes_read, sender_addr, local_ip)) => {
+                    Ok(Async::Ready((bytes_read, (sender_addr, local_ip))))
                 }
-            }
-            // TODO(povilas): prevent from DDOSing logs and put upper limit for logged buffer
-            _ => warn!("Invalid peer discovery request: {:?}", buf),
-        }
+                Async::NotReady => Ok(Async::NotReady),
+            },
+            |(sender_addr, local_ip), buf| {
+                #[cfg(feature = "wire-tap")]
+                wire_tap::observe(wire_tap, Direction::Received, &sender_addr, buf);
+                core.handle_datagram_on(sender_addr, local_ip, buf)
+            },
+            self.recv_batch_size,
+        )
     }
 
-    fn poll_send_responses(&mut self) -> io::Result<()> {
-        while let Some((addr, their_pk)) = self.clients.pop() {
-            let resp = if let Some(buf) = self.make_response(&their_pk) {
-                buf
-            } else {
-                continue;
-            };
-            match self.listener.poll_send_to(&resp, &addr)? {
-                Async::Ready(_bytes_sent) => (),
-                Async::NotReady => {
-                    self.clients.push((addr, their_pk));
-                    break;
+    fn poll_send_responses(&mut self) {
+        let listener = &mut self.listener;
+        let core = &mut self.core;
+        #[cfg(feature = "wire-tap")]
+        let wire_tap = &self.wire_tap;
+        poll_send_all(
+            |transmit| {
+                let sent = pktinfo::poll_send_from(
+                    listener,
+                    &transmit.data,
+                    &transmit.dest,
+                    transmit.source,
+                );
+                let bytes_sent = match sent? {
+                    Async::Ready(bytes_sent) => bytes_sent,
+                    Async::NotReady => return Ok(Async::NotReady),
+                };
+                #[cfg(feature = "wire-tap")]
+                wire_tap::observe(
+                    wire_tap,
+                    Direction::Sent,
+                    &transmit.dest,
+                    &transmit.data[..bytes_sent],
+                );
+                if is_fully_sent(transmit, bytes_sent) {
+                    debug!("Sent discovery response to {}", transmit.dest);
                 }
-            }
-        }
-        Ok(())
+                Ok(Async::Ready(()))
+            },
+            || core.poll_transmit(),
+            &mut self.blocked,
+            self.send_timeout,
+            Instant::now(),
+        )
     }
+}
 
-    /// Encrypt response with their public key.
-    fn make_response(&self, their_pk: &PublicEncryptKey) -> Option<Vec<u8>> {
-        let our_addrs = self
-            .our_addrs
-            .iter()
-            .map(|addr| PeerInfo::new(*addr, self.our_pk))
-            .collect();
-        let resp = DiscoveryMsg::Response(our_addrs);
-        their_pk.anonymously_encrypt(&resp).ok()
+/// Returns addresses we can listen on: the ones assigned to our network interfaces. Probing binds
+/// a TCP socket to every address.
+pub(crate) fn probe_addrs(our_addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    our_addrs
+        .into_iter()
+        .filter(|addr| {
+            let res = TcpListener::bind(SocketAddr::new(addr.ip(), 0));
+            if let Err(ref e) = res {
+                info!("Not advertising {}, can't listen on it: {}", addr, e);
+            }
+            res.is_ok()
+        }).collect()
+}
+
+/// Response that socket wasn't ready to send yet.
+pub(crate) struct BlockedTransmit {
+    transmit: Transmit,
+    /// When socket first refused to take it.
+    blocked_since: Instant,
+}
+
+/// Sends responses until there are no more of them or socket can't take any more. Response that
+/// socket was not ready to take for `send_timeout` or that failed with an error is dropped, so
+/// that a single unsendable response can't hold up the rest forever. How many times the socket
+/// was polled in the meantime doesn't matter: a busy server gets woken up a lot.
+pub(crate) fn poll_send_all<S, N>(
+    mut send: S,
+    mut next_transmit: N,
+    blocked: &mut Option<BlockedTransmit>,
+    send_timeout: Duration,
+    now: Instant,
+) where
+    S: FnMut(&Transmit) -> io::Result<Async<()>>,
+    N: FnMut() -> Option<Transmit>,
+{
+    loop {
+        let pending = match blocked.take() {
+            Some(pending) => pending,
+            None => match next_transmit() {
+                Some(transmit) => BlockedTransmit {
+                    transmit,
+                    blocked_since: now,
+                },
+                None => return,
+            },
+        };
+        match send(&pending.transmit) {
+            Ok(Async::Ready(())) => (),
+            Ok(Async::NotReady) => {
+                let blocked_for = now.duration_since(pending.blocked_since);
+                if blocked_for < send_timeout {
+                    *blocked = Some(pending);
+                    return;
+                }
+                warn!(
+                    "Dropping discovery response to {}, socket was not ready for {:?}",
+                    pending.transmit.dest, blocked_for
+                );
+            }
+            Err(e) => warn!(
+                "Failed to send discovery response to {}: {}",
+                pending.transmit.dest, e
+            ),
+        }
     }
 }
 
@@ -180,121 +834,2091 @@ impl Future for DiscoveryServer {
     type Error = DiscoveryError;
 
     fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
-        self.poll_requests().map_err(DiscoveryError::Io)?;
-        self.poll_send_responses().map_err(DiscoveryError::Io)?;
+        self.poll_addr_updates();
+        let batch_full = self.poll_requests().map_err(DiscoveryError::Io)?.is_ready();
+        self.poll_send_responses();
+        if batch_full {
+            // more requests might be waiting, but socket won't wake us up for them
+            task::current().notify();
+        }
         Ok(Async::NotReady)
     }
 }
 
-/// Broadcast peer discovery request and wait for response.
+impl Drop for DiscoveryServer {
+    fn drop(&mut self) {
+        if let Some(ref multicast) = self.multicast {
+            multicast.leave(&self.listener);
+        }
+    }
+}
+
+/// Receives datagrams until there are no more of them or `max_batch` datagrams were received.
+/// Returns `Async::Ready` in the latter case: there might be more datagrams waiting, but the
+/// current task won't be notified about them. Transient errors are logged and skipped, so that a
+/// single failed receive doesn't kill the whole server. Besides the datagram, `recv` returns its
+/// sender, possibly with more details, which is passed to `on_recv` as is.
+pub(crate) fn poll_recv_all<R, H, A>(
+    mut recv: R,
+    mut on_recv: H,
+    max_batch: usize,
+) -> io::Result<Async<()>>
+where
+    R: FnMut(&mut [u8]) -> io::Result<Async<(usize, A)>>,
+    H: FnMut(A, &[u8]),
+{
+    let mut buf = vec![0u8; MAX_MSG_SIZE];
+    let mut received = 0;
+    while received < cmp::max(max_batch, 1) {
+        match recv(&mut buf) {
+            Ok(Async::Ready((bytes_read, sender_addr))) => {
+                on_recv(sender_addr, &buf[..bytes_read]);
+                received += 1;
+            }
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Err(ref e) if is_transient_recv_error(e) => {
+                debug!("Discovery server failed to receive request: {}", e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Async::Ready(()))
+}
+
+/// Checks if socket can still be used after given receive error: interrupted calls and ICMP
+/// errors caused by our earlier responses.
+pub(crate) fn is_transient_recv_error(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::Interrupted || is_unreachable(e)
+}
+
+/// Checks if whole datagram was sent. UDP sends are normally all or nothing, but if only a part
+/// of the response went out, client won't be able to decrypt it. There's no way to take it back,
+/// so we just log it and move on to the next client instead of retrying.
+pub(crate) fn is_fully_sent(transmit: &Transmit, bytes_sent: usize) -> bool {
+    if bytes_sent == transmit.data.len() {
+        true
+    } else {
+        warn!(
+            "Truncated discovery response to {}: sent {} of {} bytes",
+            transmit.dest,
+            bytes_sent,
+            transmit.data.len()
+        );
+        false
+    }
+}
+
+/// Broadcast peer discovery request and collect responses. Every peer that answers is yielded, the
+/// stream ends once `DiscoveryConfig::response_timeout` is up for every request. Responses that
+/// can't be decrypted or decoded are logged and dropped.
 pub fn shout_for_peers(
     port: u16,
     our_pk: &PublicEncryptKey,
     our_sk: &SecretEncryptKey,
-) -> impl Stream<Item = Vec<PeerInfo>, Error = DiscoveryError> {
-    let broadcast_to = try_bstream!(broadcast_addrs(port).map_err(DiscoveryError::Io));
-    let (our_pk, our_sk) = (*our_pk, our_sk.clone());
-    let our_pk2 = our_pk;
-    let request = try_bstream!(DiscoveryMsg::serialized_request(our_pk));
-
-    stream::iter_ok(broadcast_to)
-        .and_then(move |addr| {
-            let sock = broadcast_sock().map_err(DiscoveryError::Io)?;
-            Ok((sock, addr))
-        }).and_then(move |(sock, addr)| {
-            sock.send_dgram(request.clone(), &addr)
-                .map_err(DiscoveryError::Io)
-        }).and_then(|(sock, _buf)| sock.recv_dgram(vec![0; 65000]).map_err(DiscoveryError::Io))
-        .and_then(move |(_sock, buf, bytes_read, _sender_addr)| {
-            match our_sk.anonymously_decrypt(&buf[..bytes_read], &our_pk) {
-                Ok(DiscoveryMsg::Response(their_addrs)) => Ok(their_addrs),
-                _ => Err(DiscoveryError::InvalidResponse),
-            }
-        }).map(move |peers| {
-            peers
-                .iter()
-                .filter(|peer| peer.pub_key != our_pk2)
-                .cloned()
-                .collect()
-        }).filter(|peers: &Vec<PeerInfo>| !peers.is_empty())
-        .into_boxed()
+) -> impl Stream<Item = Vec<PeerInfo>, Error = DiscoveryError> + Send {
+    shout_for_peers_with_config(port, our_pk, our_sk, &DiscoveryConfig::default())
 }
 
-// TODO(povilas): netsim test for this
-/// Returns broadcast addresses for all network interfaces on the system.
-fn broadcast_addrs(port: u16) -> io::Result<Vec<SocketAddr>> {
-    let addrs = get_if_addrs()?;
-    Ok(addrs
-        .iter()
-        .filter_map(|iface| match iface.addr {
-            IfAddr::V4(ref ifv4_addr) => ifv4_addr.broadcast,
-            IfAddr::V6(_) => None,
-        }).map(move |ip| SocketAddr::V4(SocketAddrV4::new(ip, port)))
-        .collect())
+/// Same as `shout_for_peers()` but with custom configuration.
+pub fn shout_for_peers_with_config(
+    port: u16,
+    our_pk: &PublicEncryptKey,
+    our_sk: &SecretEncryptKey,
+    config: &DiscoveryConfig,
+) -> impl Stream<Item = Vec<PeerInfo>, Error = DiscoveryError> + Send {
+    shout_for_peers_with_rtt(port, our_pk, our_sk, config).map(|discovered| discovered.peers)
 }
 
-/// Creates new UDP socket with broadcast enabled.
-fn broadcast_sock() -> io::Result<UdpSocket> {
-    let sock = UdpSocket::bind(&addr!("0.0.0.0:0"))?;
-    sock.set_broadcast(true)?;
-    Ok(sock)
+/// Peers from a single discovery response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredPeers {
+    pub peers: Vec<PeerInfo>,
+    /// Time between sending discovery request and receiving this response.
+    pub rtt: Duration,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use hamcrest2::prelude::*;
-    use tokio::runtime::current_thread::Runtime;
+/// Same as `shout_for_peers_with_config()` but also yields how long each response took, e.g. to
+/// prefer connecting to the fastest peers.
+pub fn shout_for_peers_with_rtt(
+    port: u16,
+    our_pk: &PublicEncryptKey,
+    our_sk: &SecretEncryptKey,
+    config: &DiscoveryConfig,
+) -> BoxSendStream<DiscoveredPeers, DiscoveryError> {
+    let targets = try_bstream!(target_addrs(port, config).map_err(DiscoveryError::Io));
+    shout_to(targets, our_pk, our_sk, config)
+}
 
-    #[test]
-    fn server_responds() {
-        let mut evloop = unwrap!(Runtime::new());
+/// Peer found by `discover_all()` together with every address it was reached on.
+#[derive(Clone, PartialEq, Eq)]
+pub struct MergedPeer {
+    pub pub_key: PublicEncryptKey,
+    /// Union of addresses from all of the peer's responses, in the order they arrived.
+    pub addrs: Vec<SocketAddr>,
+    /// Application data from the first response of the peer.
+    pub app_data: Vec<u8>,
+}
 
-        let (server_pk, _sk) = gen_encrypt_keypair();
-        let server = unwrap!(DiscoveryServer::new(
-            0,
-            vec![addr!("192.168.1.100:1234")],
-            &server_pk
-        ));
-        let server_addr = SocketAddr::V4(SocketAddrV4::new(ipv4!("127.0.0.1"), server.port()));
-        let sock = unwrap!(UdpSocket::bind(&addr!("0.0.0.0:0")));
+impl MergedPeer {
+    /// Constructs peer info for every address.
+    pub fn into_peers(self) -> Vec<PeerInfo> {
+        let (pub_key, app_data) = (self.pub_key, self.app_data);
+        self.addrs
+            .into_iter()
+            .map(|addr| PeerInfo::new(addr, pub_key).with_app_data(app_data.clone()))
+            .collect()
+    }
+}
 
-        let (our_pk, our_sk) = gen_encrypt_keypair();
-        let request = unwrap!(DiscoveryMsg::serialized_request(our_pk));
+/// Shows public key fingerprint only, like `PeerInfo` does.
+impl fmt::Debug for MergedPeer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MergedPeer")
+            .field("pub_key", &peer::fingerprint(&self.pub_key))
+            .field("addrs", &self.addrs)
+            .field("app_data", &self.app_data)
+            .finish()
+    }
+}
 
-        let send_req = sock
-            .send_dgram(&request, &server_addr)
-            .and_then(|(sock, _buf)| sock.recv_dgram(vec![0; 65000]))
-            .map(|(_socket, buf, bytes_received, _from)| buf[..bytes_received].to_vec())
-            .with_timeout(Duration::from_secs(2))
-            .map(|buf_opt| {
-                let buf = unwrap!(buf_opt);
-                unwrap!(our_sk.anonymously_decrypt(&buf, &our_pk))
-            }).while_driving(server);
+/// Sends discovery requests via broadcast and multicast, whichever are enabled in `config`, and
+/// resolves once the response timeout of every request is up. Peers answer on every transport and
+/// interface the request reached them on, so responses are merged by public key: every peer is
+/// returned once with the union of its addresses. Beacons are not included: they only carry a
+/// short key id. Unlike `discover_peers()`, it doesn't run a discovery server.
+pub fn discover_all(
+    port: u16,
+    our_pk: &PublicEncryptKey,
+    our_sk: &SecretEncryptKey,
+    config: &DiscoveryConfig,
+) -> impl Future<Item = Vec<MergedPeer>, Error = DiscoveryError> + Send {
+    shout_for_peers_with_config(port, our_pk, our_sk, config).fold(
+        Vec::new(),
+        |mut merged, peers| {
+            merge_peers(&mut merged, peers);
+            Ok::<_, DiscoveryError>(merged)
+        },
+    )
+}
 
-        match evloop.block_on(send_req) {
-            Ok((DiscoveryMsg::Response(addrs), _server_task)) => {
-                assert_that!(
-                    addrs,
-                    eq(vec![PeerInfo::new(addr!("192.168.1.100:1234"), server_pk)])
-                );
+/// Adds peers to the ones merged so far: addresses of already known public keys are appended to
+/// that peer unless it already has them.
+fn merge_peers(merged: &mut Vec<MergedPeer>, peers: Vec<PeerInfo>) {
+    for peer in peers {
+        match merged
+            .iter_mut()
+            .find(|known| known.pub_key == peer.pub_key)
+        {
+            Some(known) => {
+                if !known.addrs.contains(&peer.addr) {
+                    known.addrs.push(peer.addr);
+                }
             }
-            _ => panic!("Failed to send peer discovery request"),
+            None => merged.push(MergedPeer {
+                pub_key: peer.pub_key,
+                addrs: vec![peer.addr],
+                app_data: peer.app_data,
+            }),
         }
     }
+}
 
-    mod shout_for_peers {
-        use super::*;
+/// Returns addresses discovery requests should be sent to: broadcast addresses of allowed subnets
+/// and multicast group, if any. In loopback mode only `127.0.0.1` addresses are returned: `::1`
+/// would never answer, since discovery servers don't listen on IPv6.
+pub(crate) fn target_addrs(port: u16, config: &DiscoveryConfig) -> io::Result<Vec<SocketAddr>> {
+    if !config.loopback_ports.is_empty() {
+        return Ok(config
+            .loopback_ports
+            .iter()
+            .map(|port| SocketAddr::V4(SocketAddrV4::new(ipv4!("127.0.0.1"), *port)))
+            .collect());
+    }
+    let mut targets: Vec<_> = broadcast_targets(&config.interfaces.list()?, port)
+        .into_iter()
+        .filter(|addr| subnet::is_allowed(addr.ip(), &config.allowed_subnets))
+        .collect();
+    if let Some(group) = config.multicast_group {
+        targets.push(SocketAddr::V4(SocketAddrV4::new(group, port)));
+    }
+    Ok(targets)
+}
 
-        #[test]
-        fn it_broadcasts_requests_on_lan_and_collects_peer_addresses() {
-            let mut evloop = unwrap!(Runtime::new());
+/// Address discovery sends requests to, see `discovery_targets()`.
+#[derive(Debug)]
+pub struct DiscoveryTarget {
+    pub addr: SocketAddr,
+    /// Why request can't be sent to this address: socket could not be created, configured or
+    /// connected to it. `None`, if nothing is wrong.
+    pub socket_error: Option<io::Error>,
+}
 
-            let (server_pk, _server_sk) = gen_encrypt_keypair();
-            let server = unwrap!(DiscoveryServer::new(
-                0,
-                vec![addr!("192.168.1.100:1234"), addr!("127.0.0.1:1234")],
-                &server_pk,
+/// Tells where discovery with given configuration would send requests to and whether it could,
+/// without sending anything. It's a diagnostics aid for when discovery finds nothing. Every target
+/// is checked by creating the same socket discovery would and connecting it to the target, which
+/// fails if OS has no route to it.
+pub fn discovery_targets(
+    port: u16,
+    config: &DiscoveryConfig,
+) -> Result<Vec<DiscoveryTarget>, DiscoveryError> {
+    Ok(target_addrs(port, config)
+        .map_err(DiscoveryError::Io)?
+        .into_iter()
+        .map(|addr| DiscoveryTarget {
+            addr,
+            socket_error: check_target(&addr, &config.socket_options).err(),
+        }).collect())
+}
+
+fn check_target(addr: &SocketAddr, opts: &SocketOptions) -> io::Result<()> {
+    broadcast_sock(opts)?.connect(addr)
+}
+
+/// Sends discovery request to every given address and collects the responses.
+fn shout_to(
+    targets: Vec<SocketAddr>,
+    our_pk: &PublicEncryptKey,
+    our_sk: &SecretEncryptKey,
+    config: &DiscoveryConfig,
+) -> BoxSendStream<DiscoveredPeers, DiscoveryError> {
+    let (our_pk, our_sk) = (*our_pk, our_sk.clone());
+    let our_pk2 = our_pk;
+    let config = config.clone();
+    let socket_options = config.socket_options.clone();
+    let rate_limiter = config.rate_limiter.clone();
+    let response_timeout = config.response_timeout;
+    let request = try_bstream!(DiscoveryMsg::serialized_request(our_pk));
+    #[cfg(feature = "wire-tap")]
+    let (send_tap, recv_tap) = (config.wire_tap.clone(), config.wire_tap.clone());
+
+    let requests = stream::iter_ok(targets).map(move |addr| {
+        #[cfg(feature = "wire-tap")]
+        let send_tap = send_tap.clone();
+        let request = request.clone();
+        let socket_options = socket_options.clone();
+        let exchange = wait_for_slot(&rate_limiter)
+            .and_then(move |()| broadcast_sock(&socket_options).map_err(DiscoveryError::Io))
+            .and_then(move |sock| {
+                #[cfg(feature = "wire-tap")]
+                wire_tap::observe(&send_tap, Direction::Sent, &addr, &request);
+                let sent_at = Instant::now();
+                sock.send_dgram(request, &addr)
+                    .map(move |(sock, _buf)| Responses::new(sock, sent_at, response_timeout))
+                    .map_err(DiscoveryError::Io)
+            });
+        ignore_unreachable(exchange)
+            .map(|responses_opt| stream::iter_ok(responses_opt).flatten())
+            .flatten_stream()
+    });
+    FlattenUnordered::new(requests, config.max_concurrent_requests)
+        .filter_map(move |(buf, sender_addr, rtt)| {
+            #[cfg(feature = "wire-tap")]
+            wire_tap::observe(&recv_tap, Direction::Received, &sender_addr, &buf);
+            // one bad responder must not cost us the responses of everyone else
+            match decrypt_response(&buf, &our_pk, &our_sk) {
+                Ok(resp) => Some((resp.into_peers(), rtt)),
+                Err(e) => {
+                    debug!("Dropping invalid response from {}: {:?}", sender_addr, e);
+                    None
+                }
+            }
+        }).map(move |(peers, rtt)| DiscoveredPeers {
+            peers: peers
+                .iter()
+                .filter(|peer| is_acceptable_peer(peer, &our_pk2, &config))
+                .cloned()
+                .collect(),
+            rtt,
+        }).filter(|discovered| !discovered.peers.is_empty())
+        .into_send_boxed()
+}
+
+/// Datagrams received in response to a single discovery request, together with their sender
+/// and the time since the request was sent. Ends once the response timeout is up or there's no
+/// peer on the other end.
+struct Responses {
+    sock: UdpSocket,
+    sent_at: Instant,
+    timeout: Delay,
+    buf: Vec<u8>,
+}
+
+impl Responses {
+    fn new(sock: UdpSocket, sent_at: Instant, timeout: Duration) -> Self {
+        Self {
+            sock,
+            sent_at,
+            timeout: Delay::new(sent_at + timeout),
+            buf: vec![0; MAX_MSG_SIZE],
+        }
+    }
+}
+
+impl Stream for Responses {
+    type Item = (Vec<u8>, SocketAddr, Duration);
+    type Error = DiscoveryError;
+
+    fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
+        match self.sock.poll_recv_from(&mut self.buf) {
+            Ok(Async::Ready((bytes_read, sender_addr))) => {
+                let resp = self.buf[..bytes_read].to_vec();
+                let rtt = self.sent_at.elapsed();
+                return Ok(Async::Ready(Some((resp, sender_addr, rtt))));
+            }
+            Ok(Async::NotReady) => (),
+            Err(e) => {
+                skip_unreachable(DiscoveryError::Io(e))?;
+                return Ok(Async::Ready(None));
+            }
+        }
+        match self.timeout.poll() {
+            Ok(Async::Ready(())) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => Err(DiscoveryError::Io(io::Error::new(io::ErrorKind::Other, e))),
+        }
+    }
+}
+
+/// Polls at most `max_active` streams taken from a given stream of streams at once and yields
+/// their items in the order they arrive. Once an active stream ends, the next one is taken.
+struct FlattenUnordered<S: Stream> {
+    pending: stream::Fuse<S>,
+    active: Vec<S::Item>,
+    max_active: usize,
+}
+
+impl<S: Stream> FlattenUnordered<S> {
+    /// Values of `max_active` below 1 are treated as 1.
+    fn new(streams: S, max_active: usize) -> Self {
+        Self {
+            pending: streams.fuse(),
+            active: Vec::new(),
+            max_active: cmp::max(max_active, 1),
+        }
+    }
+}
+
+impl<S> Stream for FlattenUnordered<S>
+where
+    S: Stream,
+    S::Item: Stream<Error = S::Error>,
+{
+    type Item = <S::Item as Stream>::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
+        loop {
+            while self.active.len() < self.max_active {
+                match self.pending.poll()? {
+                    Async::Ready(Some(stream)) => self.active.push(stream),
+                    Async::Ready(None) | Async::NotReady => break,
+                }
+            }
+            let mut any_ended = false;
+            let mut i = 0;
+            while i < self.active.len() {
+                match self.active[i].poll()? {
+                    Async::Ready(Some(item)) => return Ok(Async::Ready(Some(item))),
+                    Async::Ready(None) => {
+                        let _ = self.active.swap_remove(i);
+                        any_ended = true;
+                    }
+                    Async::NotReady => i += 1,
+                }
+            }
+            if !any_ended {
+                break;
+            }
+        }
+        if self.active.is_empty() && self.pending.is_done() {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// Resolves once rate limiter, if any, lets us send a request.
+fn wait_for_slot(
+    rate_limiter: &Option<RateLimiter>,
+) -> impl Future<Item = (), Error = DiscoveryError> {
+    let send_at = rate_limiter
+        .as_ref()
+        .map(RateLimiter::reserve)
+        .unwrap_or_else(Instant::now);
+    Delay::new(send_at).map_err(|e| DiscoveryError::Io(io::Error::new(io::ErrorKind::Other, e)))
+}
+
+/// Decrypts and deserializes discovery response sent to us.
+pub(crate) fn decrypt_response(
+    buf: &[u8],
+    our_pk: &PublicEncryptKey,
+    our_sk: &SecretEncryptKey,
+) -> Result<DiscoveryResponse, DiscoveryError> {
+    let plaintext = our_sk
+        .anonymously_decrypt_bytes(buf, our_pk)
+        .map_err(DiscoveryError::DecryptFailed)?;
+    let msg = DiscoveryMsg::deserialize(&plaintext).map_err(|e| match e {
+        DiscoveryError::SerializeFailure(e) => DiscoveryError::DeserializeFailed(e),
+        e => e,
+    })?;
+    match msg {
+        DiscoveryMsg::Response(resp) => Ok(resp),
+        _ => Err(DiscoveryError::InvalidResponse),
+    }
+}
+
+/// Some platforms report ICMP port unreachable as a receive error. It only means that no peer is
+/// listening on that address, so instead of failing the whole discovery such errors resolve to
+/// `None`. So do network and host unreachable errors: they happen when an interface goes down
+/// mid-discovery, e.g. while Wi-Fi is being toggled, and only affect that interface.
+fn ignore_unreachable<F>(f: F) -> impl Future<Item = Option<F::Item>, Error = DiscoveryError>
+where
+    F: Future<Error = DiscoveryError>,
+{
+    f.map(Some).or_else(|e| skip_unreachable(e).map(|()| None))
+}
+
+/// Logs and swallows errors that only mean there's no peer on the other end, see
+/// `ignore_unreachable()`. Other errors are returned as is.
+fn skip_unreachable(e: DiscoveryError) -> Result<(), DiscoveryError> {
+    match e {
+        DiscoveryError::Io(ref e) if is_unreachable(e) => {
+            debug!("No peer on the other end: {}", e);
+            Ok(())
+        }
+        DiscoveryError::Io(ref e) if is_network_unreachable(e) => {
+            info!("Skipping unreachable network: {}", e);
+            Ok(())
+        }
+        e => Err(e),
+    }
+}
+
+/// Checks if IO error is the result of ICMP port unreachable.
+fn is_unreachable(e: &io::Error) -> bool {
+    match e.kind() {
+        // Windows reports ICMP port unreachable as connection reset
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset => true,
+        _ => false,
+    }
+}
+
+/// Checks if IO error means there's no route to the destination right now.
+fn is_network_unreachable(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NetworkUnreachable | io::ErrorKind::HostUnreachable
+    )
+}
+
+/// Checks if discovered peer should be yielded to the caller.
+pub(crate) fn is_acceptable_peer(
+    peer: &PeerInfo,
+    our_pk: &PublicEncryptKey,
+    config: &DiscoveryConfig,
+) -> bool {
+    (config.include_self || peer.pub_key != *our_pk)
+        && subnet::is_allowed(peer.addr.ip(), &config.allowed_subnets)
+}
+
+/// Returns broadcast addresses of given interfaces. Only IPv4 interfaces have them, loopback is
+/// skipped: requests to it could only reach ourselves.
+fn broadcast_targets(ifaces: &[Interface], port: u16) -> Vec<SocketAddr> {
+    ifaces
+        .iter()
+        .filter(|iface| !iface.is_loopback())
+        .filter_map(|iface| match iface.addr {
+            IfAddr::V4(ref ifv4_addr) => ifv4_addr.broadcast,
+            IfAddr::V6(_) => None,
+        }).map(move |ip| SocketAddr::V4(SocketAddrV4::new(ip, port)))
+        .collect()
+}
+
+/// Creates new UDP socket with broadcast enabled.
+pub(crate) fn broadcast_sock(opts: &SocketOptions) -> io::Result<UdpSocket> {
+    let sock = socket::bind_udp(&addr!("0.0.0.0:0"), opts)?;
+    sock.set_broadcast(true)?;
+    Ok(sock)
+}
+
+/// Multicast group socket has joined and the local interface addresses it joined on.
+#[derive(Debug)]
+pub(crate) struct MulticastMembership {
+    group: Ipv4Addr,
+    iface_ips: Vec<Ipv4Addr>,
+}
+
+impl MulticastMembership {
+    /// Joins `config.multicast_group`, if any, on every allowed IPv4 interface, so that requests
+    /// sent to the group are received whichever interface they arrive on. Interfaces the group
+    /// can't be joined on are skipped. When it can't be joined on any, it's joined on the default
+    /// interface, the one OS picks.
+    pub fn join(sock: &UdpSocket, config: &DiscoveryConfig) -> io::Result<Option<Self>> {
+        let group = match config.multicast_group {
+            Some(group) => group,
+            None => return Ok(None),
+        };
+        let mut iface_names = Vec::new();
+        let mut iface_ips = Vec::new();
+        for iface in config.interfaces.list()? {
+            let ip = match iface.addr {
+                IfAddr::V4(ref addr) => addr.ip,
+                IfAddr::V6(_) => continue,
+            };
+            if !subnet::is_allowed(IpAddr::V4(ip), &config.allowed_subnets)
+                || iface_names.contains(&iface.name)
+            {
+                continue;
+            }
+            match sock.join_multicast_v4(&group, &ip) {
+                Ok(()) => {
+                    iface_names.push(iface.name);
+                    iface_ips.push(ip);
+                }
+                Err(e) => info!("Failed to join {} on {}: {}", group, iface.name, e),
+            }
+        }
+        if iface_ips.is_empty() {
+            sock.join_multicast_v4(&group, &ipv4!("0.0.0.0"))?;
+            iface_ips.push(ipv4!("0.0.0.0"));
+        }
+        Ok(Some(Self { group, iface_ips }))
+    }
+
+    /// Leaves the group on every interface it was joined on.
+    pub fn leave(&self, sock: &UdpSocket) {
+        for ip in &self.iface_ips {
+            if let Err(e) = sock.leave_multicast_v4(&self.group, ip) {
+                debug!("Failed to leave {} on {}: {}", self.group, ip, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use get_if_addrs::{Ifv4Addr, Ifv6Addr};
+    use hamcrest2::prelude::*;
+    use std::thread;
+    use std::time::Instant;
+    use testing::{MockBehavior, MockDiscoveryServer};
+    use tokio::reactor::Reactor;
+    use tokio::runtime::current_thread::Runtime;
+    #[cfg(target_os = "linux")]
+    use virtual_lan::{VirtualLan, LAN_BROADCAST};
+
+    #[test]
+    fn server_responds() {
+        let mut evloop = unwrap!(Runtime::new());
+
+        let (server_pk, _sk) = gen_encrypt_keypair();
+        let server = unwrap!(DiscoveryServer::new(
+            0,
+            vec![addr!("192.168.1.100:1234")],
+            &server_pk
+        ));
+        let server_addr = SocketAddr::V4(SocketAddrV4::new(ipv4!("127.0.0.1"), server.port()));
+        let sock = unwrap!(UdpSocket::bind(&addr!("0.0.0.0:0")));
+
+        let (our_pk, our_sk) = gen_encrypt_keypair();
+        let request = unwrap!(DiscoveryMsg::serialized_request(our_pk));
+
+        let send_req = sock
+            .send_dgram(&request, &server_addr)
+            .and_then(|(sock, _buf)| sock.recv_dgram(vec![0; 65000]))
+            .map(|(_socket, buf, bytes_received, _from)| buf[..bytes_received].to_vec())
+            .with_timeout(Duration::from_secs(2))
+            .map(|buf_opt| {
+                let buf = unwrap!(buf_opt);
+                let plaintext = unwrap!(our_sk.anonymously_decrypt_bytes(&buf, &our_pk));
+                unwrap!(DiscoveryMsg::deserialize(&plaintext))
+            }).while_driving(server);
+
+        match evloop.block_on(send_req) {
+            Ok((DiscoveryMsg::Response(resp), _server_task)) => {
+                assert_that!(
+                    resp.into_peers(),
+                    eq(vec![PeerInfo::new(addr!("192.168.1.100:1234"), server_pk)])
+                );
+            }
+            _ => panic!("Failed to send peer discovery request"),
+        }
+    }
+
+    #[test]
+    fn server_responds_from_the_address_it_is_bound_to() {
+        let mut evloop = unwrap!(Runtime::new());
+
+        let (server_pk, _sk) = gen_encrypt_keypair();
+        let config = DiscoveryConfig {
+            bind_ip: Some(ipv4!("127.0.0.2")),
+            ..Default::default()
+        };
+        let server = unwrap!(DiscoveryServer::with_config(
+            0,
+            vec![addr!("192.168.1.100:1234")],
+            &server_pk,
+            &config,
+        ));
+        let server_addr = SocketAddr::V4(SocketAddrV4::new(ipv4!("127.0.0.2"), server.port()));
+        let sock = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0")));
+
+        let (our_pk, _our_sk) = gen_encrypt_keypair();
+        let request = unwrap!(DiscoveryMsg::serialized_request(our_pk));
+
+        let send_req = sock
+            .send_dgram(&request, &server_addr)
+            .and_then(|(sock, _buf)| sock.recv_dgram(vec![0; 65000]))
+            .map(|(_socket, _buf, _bytes_received, from)| from)
+            .with_timeout(Duration::from_secs(2))
+            .while_driving(server);
+
+        match evloop.block_on(send_req) {
+            Ok((resp_from, _server_task)) => assert_that!(resp_from, eq(Some(server_addr))),
+            _ => panic!("Failed to send peer discovery request"),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn server_bound_to_any_address_responds_from_the_address_request_was_sent_to() {
+        let mut evloop = unwrap!(Runtime::new());
+
+        let (server_pk, _sk) = gen_encrypt_keypair();
+        let server = unwrap!(DiscoveryServer::new(
+            0,
+            vec![addr!("192.168.1.100:1234")],
+            &server_pk
+        ));
+        let server_addr1 = SocketAddr::V4(SocketAddrV4::new(ipv4!("127.0.0.1"), server.port()));
+        let server_addr2 = SocketAddr::V4(SocketAddrV4::new(ipv4!("127.0.0.2"), server.port()));
+        let sock = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0")));
+
+        let (our_pk, _our_sk) = gen_encrypt_keypair();
+        let request = unwrap!(DiscoveryMsg::serialized_request(our_pk));
+
+        let send_reqs = sock
+            .send_dgram(request.clone(), &server_addr1)
+            .and_then(|(sock, _buf)| sock.recv_dgram(vec![0; 65000]))
+            .and_then(move |(sock, _buf, _bytes_received, from1)| {
+                sock.send_dgram(request, &server_addr2)
+                    .and_then(|(sock, _buf)| sock.recv_dgram(vec![0; 65000]))
+                    .map(move |(_sock, _buf, _bytes_received, from2)| (from1, from2))
+            }).with_timeout(Duration::from_secs(2))
+            .while_driving(server);
+
+        match evloop.block_on(send_reqs) {
+            Ok((resp_from, _server_task)) => {
+                assert_that!(resp_from, eq(Some((server_addr1, server_addr2))))
+            }
+            _ => panic!("Failed to send peer discovery requests"),
+        }
+    }
+
+    #[test]
+    fn server_reports_addresses_it_is_bound_to() {
+        let (server_pk, _sk) = gen_encrypt_keypair();
+        let server = unwrap!(DiscoveryServer::new(
+            0,
+            vec![addr!("192.168.1.100:1234")],
+            &server_pk
+        ));
+        let config = DiscoveryConfig {
+            bind_ip: Some(ipv4!("127.0.0.2")),
+            ..Default::default()
+        };
+        let bound_server = unwrap!(DiscoveryServer::with_config(
+            0,
+            vec![addr!("192.168.1.100:1234")],
+            &server_pk,
+            &config,
+        ));
+
+        assert_that!(server.port(), not(eq(0)));
+        assert_that!(
+            server.bound_addrs(),
+            eq(vec![SocketAddr::V4(SocketAddrV4::new(
+                ipv4!("0.0.0.0"),
+                server.port()
+            ))])
+        );
+        assert_that!(
+            bound_server.bound_addrs(),
+            eq(vec![SocketAddr::V4(SocketAddrV4::new(
+                ipv4!("127.0.0.2"),
+                bound_server.port()
+            ))])
+        );
+    }
+
+    #[test]
+    fn probe_addrs_skips_addresses_that_are_not_ours() {
+        let addrs = probe_addrs(vec![addr!("127.0.0.1:1234"), addr!("192.0.2.1:1234")]);
+
+        assert_that!(addrs, eq(vec![addr!("127.0.0.1:1234")]));
+    }
+
+    #[test]
+    fn server_construction_fails_when_none_of_probed_addresses_are_ours() {
+        let (server_pk, _sk) = gen_encrypt_keypair();
+        let config = DiscoveryConfig {
+            probe_our_addrs: true,
+            ..Default::default()
+        };
+
+        let res =
+            DiscoveryServer::with_config(0, vec![addr!("192.0.2.1:1234")], &server_pk, &config);
+
+        match res {
+            Err(DiscoveryError::NoAddrs) => (),
+            _ => panic!("Expected NoAddrs error"),
+        }
+    }
+
+    #[test]
+    fn server_responds_with_addresses_received_from_channel() {
+        let mut evloop = unwrap!(Runtime::new());
+
+        let (server_pk, _sk) = gen_encrypt_keypair();
+        let mut server = unwrap!(DiscoveryServer::new(
+            0,
+            vec![addr!("192.168.1.100:1234")],
+            &server_pk
+        ));
+        let (mut updates_tx, updates_rx) = mpsc::channel(4);
+        server.set_addr_updates(updates_rx);
+        unwrap!(updates_tx.try_send(vec![addr!("192.168.1.101:1234")]));
+        unwrap!(updates_tx.try_send(vec![addr!("10.0.0.1:1234")]));
+        let server_port = server.port();
+
+        let (our_pk, our_sk) = gen_encrypt_keypair();
+        let config = DiscoveryConfig {
+            loopback_ports: vec![server_port],
+            ..Default::default()
+        };
+        let task = shout_for_peers_with_config(server_port, &our_pk, &our_sk, &config)
+            .collect()
+            .with_timeout(Duration::from_secs(10))
+            .while_driving(server);
+
+        match evloop.block_on(task) {
+            Ok((Some(peers), _server)) => {
+                assert_that!(
+                    peers,
+                    eq(vec![vec![PeerInfo::new(addr!("10.0.0.1:1234"), server_pk)]])
+                );
+            }
+            _ => panic!("Peer discovery failed"),
+        }
+    }
+
+    #[test]
+    fn server_construction_fails_when_port_is_taken() {
+        let (server_pk, _sk) = gen_encrypt_keypair();
+        let taken = unwrap!(UdpSocket::bind(&addr!("0.0.0.0:0")));
+        let port = unwrap!(taken.local_addr()).port();
+
+        let res = DiscoveryServer::new(port, vec![addr!("192.168.1.100:1234")], &server_pk);
+
+        match res {
+            Err(DiscoveryError::PortInUse(p)) => assert_that!(p, eq(port)),
+            _ => panic!("Expected PortInUse error"),
+        }
+    }
+
+    #[test]
+    fn bind_error_tells_privileged_ports_apart() {
+        let e = io::Error::new(io::ErrorKind::PermissionDenied, "EACCES");
+
+        match bind_error(80, e) {
+            DiscoveryError::PortRequiresPrivileges(port) => assert_that!(port, eq(80)),
+            _ => panic!("Expected PortRequiresPrivileges error"),
+        }
+    }
+
+    #[test]
+    fn bind_error_keeps_other_errors_as_is() {
+        let e = io::Error::new(io::ErrorKind::AddrNotAvailable, "EADDRNOTAVAIL");
+
+        match bind_error(5000, e) {
+            DiscoveryError::Io(e) => assert_that!(e.kind(), eq(io::ErrorKind::AddrNotAvailable)),
+            _ => panic!("Expected IO error"),
+        }
+    }
+
+    #[test]
+    fn server_runs_on_explicitly_given_reactor() {
+        let reactor = unwrap!(unwrap!(Reactor::new()).background());
+        let (server_pk, _sk) = gen_encrypt_keypair();
+        let config = DiscoveryConfig {
+            socket_options: SocketOptions {
+                reactor: Some(reactor.handle().clone()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let server = unwrap!(DiscoveryServer::with_config(
+            0,
+            vec![addr!("192.168.1.100:1234")],
+            &server_pk,
+            &config,
+        ));
+        let server_addr = SocketAddr::V4(SocketAddrV4::new(ipv4!("127.0.0.1"), server.port()));
+        let _ = thread::spawn(move || server.wait());
+
+        let sock = unwrap!(std::net::UdpSocket::bind("127.0.0.1:0"));
+        unwrap!(sock.set_read_timeout(Some(Duration::from_secs(2))));
+        let (our_pk, our_sk) = gen_encrypt_keypair();
+        let request = unwrap!(DiscoveryMsg::serialized_request(our_pk));
+        let _ = unwrap!(sock.send_to(&request, server_addr));
+        let mut buf = vec![0; 65000];
+        let (bytes_read, _) = unwrap!(sock.recv_from(&mut buf));
+
+        let plaintext = unwrap!(our_sk.anonymously_decrypt_bytes(&buf[..bytes_read], &our_pk));
+        match unwrap!(DiscoveryMsg::deserialize(&plaintext)) {
+            DiscoveryMsg::Response(resp) => assert_that!(resp.pub_key, eq(server_pk)),
+            _ => panic!("Expected discovery response"),
+        }
+    }
+
+    #[test]
+    fn server_runs_on_multi_threaded_runtime() {
+        let mut evloop = unwrap!(tokio::runtime::Runtime::new());
+
+        let (server_pk, _server_sk) = gen_encrypt_keypair();
+        let server = unwrap!(DiscoveryServer::new(
+            0,
+            vec![addr!("192.168.1.100:1234")],
+            &server_pk
+        ));
+        let server_port = server.port();
+        evloop.spawn(
+            server
+                .map(|_| ())
+                .map_err(|e| panic!("Discovery server failed: {:?}", e)),
+        );
+
+        let (our_pk, our_sk) = gen_encrypt_keypair();
+        let find_peers = shout_for_peers(server_port, &our_pk, &our_sk)
+            .collect()
+            .with_timeout(Duration::from_secs(10));
+        let their_addrs = unwrap!(unwrap!(evloop.block_on(find_peers)));
+
+        assert_that!(
+            their_addrs,
+            eq(vec![vec![PeerInfo::new(
+                addr!("192.168.1.100:1234"),
+                server_pk
+            )]])
+        );
+    }
+
+    #[test]
+    fn server_construction_fails_when_app_data_is_too_long() {
+        let (server_pk, _sk) = gen_encrypt_keypair();
+        let res = DiscoveryServer::with_app_data(
+            0,
+            vec![addr!("192.168.1.100:1234")],
+            &server_pk,
+            vec![1; MAX_APP_DATA_LEN + 1],
+        );
+
+        match res {
+            Err(DiscoveryError::AppDataTooLong(len)) => assert_that!(len, eq(MAX_APP_DATA_LEN + 1)),
+            _ => panic!("Expected AppDataTooLong error"),
+        }
+    }
+
+    mod estimated_response_size {
+        use super::*;
+
+        #[test]
+        fn it_matches_the_size_of_real_response() {
+            let (their_pk, _) = gen_encrypt_keypair();
+            let (our_pk, _) = gen_encrypt_keypair();
+            let our_addrs = vec![addr!("192.168.1.100:1234"), addr!("[fe80::1]:1234")];
+            let app_data = b"Laptop".to_vec();
+            let resp = DiscoveryResponse {
+                pub_key: our_pk,
+                addrs: our_addrs.clone(),
+                app_data: app_data.clone(),
+            };
+
+            let resp = unwrap!(DiscoveryMsg::encrypted_response(&their_pk, resp));
+
+            assert_that!(
+                estimated_response_size(&our_addrs, &app_data),
+                eq(resp.len())
+            );
+        }
+
+        #[test]
+        fn encryption_overhead_matches_what_anonymous_encryption_adds() {
+            let (pub_key, _) = gen_encrypt_keypair();
+            let plaintext = [5; 100];
+
+            let encrypted = pub_key.anonymously_encrypt_bytes(&plaintext);
+
+            assert_that!(ENCRYPTION_OVERHEAD, eq(encrypted.len() - plaintext.len()));
+        }
+    }
+
+    mod discovery_msg {
+        use super::*;
+        use bincode::ErrorKind;
+
+        #[test]
+        fn deserialize_fails_when_message_exceeds_size_limit() {
+            let (pub_key, _) = gen_encrypt_keypair();
+            let msg = DiscoveryMsg::Response(DiscoveryResponse {
+                pub_key,
+                addrs: vec![],
+                app_data: vec![1; MAX_MSG_SIZE],
+            });
+            let buf = unwrap!(msg.serialize());
+
+            match DiscoveryMsg::deserialize(&buf) {
+                Err(DiscoveryError::SerializeFailure(e)) => match *e {
+                    ErrorKind::SizeLimit => (),
+                    e => panic!("Unexpected error: {:?}", e),
+                },
+                res => panic!("Unexpected result: {:?}", res),
+            }
+        }
+
+        #[test]
+        fn deserialize_fails_gracefully_on_crafted_length_fields() {
+            let lengths: [[u8; 8]; 4] = [
+                [0xff; 8],
+                [0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0],
+                [0, 0, 0, 0, 0, 0, 0, 0x80],
+                [0xe8, 0xfd, 0, 0, 0, 0, 0, 0],
+            ];
+            for len in &lengths {
+                // header, response tag, public key and then bogus address count
+                let mut buf = vec![b'L', b'D', PROTOCOL_VERSION, 1, 0, 0, 0];
+                buf.extend_from_slice(&[7; 32]);
+                buf.extend_from_slice(len);
+                buf.extend_from_slice(&[0; 64]);
+
+                assert_that!(DiscoveryMsg::deserialize(&buf).is_err(), is(true));
+            }
+        }
+
+        #[test]
+        fn serialized_message_starts_with_magic_and_protocol_version() {
+            let (pub_key, _) = gen_encrypt_keypair();
+
+            let buf = unwrap!(DiscoveryMsg::serialized_request(pub_key));
+
+            assert_that!(&buf[..3], eq(&[b'L', b'D', PROTOCOL_VERSION][..]));
+            match unwrap!(DiscoveryMsg::deserialize(&buf)) {
+                DiscoveryMsg::Request(pk) => assert_that!(pk, eq(pub_key)),
+                msg => panic!("Unexpected message: {:?}", msg),
+            }
+        }
+
+        #[test]
+        fn deserialize_reports_messages_of_other_protocol_versions() {
+            let (pub_key, _) = gen_encrypt_keypair();
+            let mut buf = unwrap!(DiscoveryMsg::serialized_request(pub_key));
+            buf[2] = PROTOCOL_VERSION + 1;
+
+            match DiscoveryMsg::deserialize(&buf) {
+                Err(DiscoveryError::UnsupportedVersion(version)) => {
+                    assert_that!(version, eq(PROTOCOL_VERSION + 1))
+                }
+                res => panic!("Unexpected result: {:?}", res),
+            }
+        }
+
+        #[test]
+        fn deserialize_reports_unversioned_requests_as_version_0() {
+            let (pub_key, _) = gen_encrypt_keypair();
+            let mut buf = vec![0, 0, 0, 0];
+            buf.extend_from_slice(&pub_key.into_bytes());
+
+            match DiscoveryMsg::deserialize(&buf) {
+                Err(DiscoveryError::UnsupportedVersion(version)) => assert_that!(version, eq(0)),
+                res => panic!("Unexpected result: {:?}", res),
+            }
+        }
+
+        #[test]
+        fn deserialize_fails_on_garbage() {
+            match DiscoveryMsg::deserialize(&[0xff; 40]) {
+                Err(DiscoveryError::SerializeFailure(_)) => (),
+                res => panic!("Unexpected result: {:?}", res),
+            }
+        }
+    }
+
+    mod ignore_unreachable {
+        use super::*;
+        use futures::future;
+
+        #[test]
+        fn it_skips_addresses_reporting_port_unreachable() {
+            let (their_pk, _) = gen_encrypt_keypair();
+            let their_peers = vec![PeerInfo::new(addr!("192.168.1.100:1234"), their_pk)];
+            let responses = vec![
+                Err(io::Error::new(io::ErrorKind::ConnectionRefused, "ICMP")),
+                Ok(their_peers.clone()),
+                Err(io::Error::new(io::ErrorKind::ConnectionReset, "ICMP")),
+            ];
+
+            let peers = stream::iter_ok(responses)
+                .map(|resp| resp.map_err(DiscoveryError::Io))
+                .and_then(|resp| ignore_unreachable(future::result(resp)))
+                .filter_map(|peers_opt| peers_opt)
+                .collect()
+                .wait();
+
+            assert_that!(unwrap!(peers), eq(vec![their_peers]));
+        }
+
+        #[test]
+        fn it_skips_unreachable_networks() {
+            let (their_pk, _) = gen_encrypt_keypair();
+            let their_peers = vec![PeerInfo::new(addr!("192.168.1.100:1234"), their_pk)];
+            let responses = vec![
+                Err(io::Error::from(io::ErrorKind::NetworkUnreachable)),
+                Ok(their_peers.clone()),
+                Err(io::Error::from(io::ErrorKind::HostUnreachable)),
+            ];
+
+            let peers = stream::iter_ok(responses)
+                .map(|resp| resp.map_err(DiscoveryError::Io))
+                .and_then(|resp| ignore_unreachable(future::result(resp)))
+                .filter_map(|peers_opt| peers_opt)
+                .collect()
+                .wait();
+
+            assert_that!(unwrap!(peers), eq(vec![their_peers]));
+        }
+
+        #[test]
+        fn it_propagates_other_errors() {
+            let resp: Result<(), _> = Err(io::Error::new(io::ErrorKind::PermissionDenied, "no"));
+
+            let res = ignore_unreachable(future::result(resp.map_err(DiscoveryError::Io))).wait();
+
+            match res {
+                Err(DiscoveryError::Io(ref e)) if e.kind() == io::ErrorKind::PermissionDenied => (),
+                res => panic!("Unexpected result: {:?}", res),
+            }
+        }
+    }
+
+    mod poll_recv_all {
+        use super::*;
+
+        #[test]
+        fn it_keeps_receiving_after_transient_errors() {
+            let mut results = vec![
+                Err(io::Error::new(io::ErrorKind::Interrupted, "EINTR")),
+                Err(io::Error::new(io::ErrorKind::ConnectionReset, "ICMP")),
+                Ok(Async::Ready((3, addr!("192.168.1.2:5000")))),
+                Ok(Async::NotReady),
+            ].into_iter();
+            let mut received = Vec::new();
+
+            let res = poll_recv_all(
+                |buf| {
+                    buf[..3].copy_from_slice(&[1, 2, 3]);
+                    unwrap!(results.next())
+                },
+                |addr, buf| received.push((addr, buf.to_vec())),
+                DEFAULT_RECV_BATCH_SIZE,
+            );
+
+            assert_that!(unwrap!(res).is_not_ready(), is(true));
+            assert_that!(
+                received,
+                eq(vec![(addr!("192.168.1.2:5000"), vec![1, 2, 3])])
+            );
+        }
+
+        #[test]
+        fn it_fails_on_fatal_errors() {
+            let res = poll_recv_all(
+                |_buf| Err(io::Error::new(io::ErrorKind::NotConnected, "closed")),
+                |_addr: SocketAddr, _buf| panic!("Nothing should be received"),
+                DEFAULT_RECV_BATCH_SIZE,
+            );
+
+            match res {
+                Err(e) => assert_that!(e.kind(), eq(io::ErrorKind::NotConnected)),
+                Ok(_) => panic!("Expected error"),
+            }
+        }
+
+        #[test]
+        fn it_stops_after_receiving_max_batch_of_datagrams() {
+            let mut received = 0;
+
+            let res = poll_recv_all(
+                |_buf| Ok(Async::Ready((3, addr!("192.168.1.2:5000")))),
+                |_addr, _buf| received += 1,
+                10,
+            );
+
+            assert_that!(unwrap!(res).is_ready(), is(true));
+            assert_that!(received, eq(10));
+        }
+
+        #[test]
+        fn server_keeps_responding_when_requests_exceed_batch_size() {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let (server_pk, _sk) = gen_encrypt_keypair();
+            let config = DiscoveryConfig {
+                recv_batch_size: 1,
+                ..Default::default()
+            };
+            let server = unwrap!(DiscoveryServer::with_config(
+                0,
+                vec![addr!("192.168.1.100:1234")],
+                &server_pk,
+                &config,
+            ));
+            let server_addr = SocketAddr::V4(SocketAddrV4::new(ipv4!("127.0.0.1"), server.port()));
+            let sock = unwrap!(std::net::UdpSocket::bind("127.0.0.1:0"));
+            let (our_pk, _our_sk) = gen_encrypt_keypair();
+            let request = unwrap!(DiscoveryMsg::serialized_request(our_pk));
+            for _ in 0..50 {
+                let _ = unwrap!(sock.send_to(&request, server_addr));
+            }
+            // server runs in its own task, so only its socket can wake it up
+            evloop.spawn(
+                server
+                    .map(|_| ())
+                    .map_err(|e| panic!("Server failed: {:?}", e)),
+            );
+            let _ = evloop.block_on(Delay::new(Instant::now() + Duration::from_millis(500)));
+
+            unwrap!(sock.set_read_timeout(Some(Duration::from_secs(1))));
+            let mut buf = vec![0; 65000];
+            for _ in 0..50 {
+                let _ = unwrap!(sock.recv_from(&mut buf), "Server stopped responding");
+            }
+        }
+    }
+
+    mod poll_send_all {
+        use super::*;
+
+        fn transmit(port: u16) -> Transmit {
+            Transmit {
+                dest: SocketAddr::V4(SocketAddrV4::new(ipv4!("192.168.1.2"), port)),
+                data: vec![1, 2, 3],
+                source: None,
+            }
+        }
+
+        #[test]
+        fn it_keeps_blocked_response_regardless_of_how_many_times_it_is_polled() {
+            let mut queue = vec![transmit(1)];
+            let mut blocked = None;
+            let now = Instant::now();
+
+            for _ in 0..100 {
+                poll_send_all(
+                    |_transmit| Ok(Async::NotReady),
+                    || queue.pop(),
+                    &mut blocked,
+                    Duration::from_secs(1),
+                    now,
+                );
+            }
+
+            assert_that!(blocked.is_some(), is(true));
+        }
+
+        #[test]
+        fn it_drops_response_socket_does_not_take_within_send_timeout() {
+            let mut queue = vec![transmit(2), transmit(1)];
+            let mut sent = Vec::new();
+            let mut blocked = None;
+            let now = Instant::now();
+
+            poll_send_all(
+                |_transmit| Ok(Async::NotReady),
+                || queue.pop(),
+                &mut blocked,
+                Duration::from_secs(1),
+                now,
+            );
+            poll_send_all(
+                |transmit| {
+                    if transmit.dest.port() == 1 {
+                        Ok(Async::NotReady)
+                    } else {
+                        sent.push(transmit.dest.port());
+                        Ok(Async::Ready(()))
+                    }
+                },
+                || queue.pop(),
+                &mut blocked,
+                Duration::from_secs(1),
+                now + Duration::from_secs(1),
+            );
+
+            assert_that!(sent, eq(vec![2]));
+            assert_that!(blocked.is_none(), is(true));
+        }
+
+        #[test]
+        fn it_moves_on_to_next_response_when_send_fails() {
+            let mut queue = vec![transmit(2), transmit(1)];
+            let mut sent = Vec::new();
+            let mut blocked = None;
+
+            poll_send_all(
+                |transmit| {
+                    if transmit.dest.port() == 1 {
+                        Err(io::Error::new(io::ErrorKind::NotFound, "no route"))
+                    } else {
+                        sent.push(transmit.dest.port());
+                        Ok(Async::Ready(()))
+                    }
+                },
+                || queue.pop(),
+                &mut blocked,
+                Duration::from_secs(1),
+                Instant::now(),
+            );
+
+            assert_that!(sent, eq(vec![2]));
+            assert_that!(blocked.is_none(), is(true));
+        }
+
+        #[test]
+        fn it_retries_blocked_response_first() {
+            let mut queue = vec![transmit(2), transmit(1)];
+            let mut sent = Vec::new();
+            let mut blocked = None;
+            let mut socket_ready = false;
+
+            for _ in 0..2 {
+                poll_send_all(
+                    |transmit| {
+                        if socket_ready {
+                            sent.push(transmit.dest.port());
+                            Ok(Async::Ready(()))
+                        } else {
+                            Ok(Async::NotReady)
+                        }
+                    },
+                    || queue.pop(),
+                    &mut blocked,
+                    Duration::from_secs(1),
+                    Instant::now(),
+                );
+                socket_ready = true;
+            }
+
+            assert_that!(sent, eq(vec![1, 2]));
+        }
+    }
+
+    mod is_fully_sent {
+        use super::*;
+
+        #[test]
+        fn it_returns_false_when_only_part_of_datagram_was_sent() {
+            let transmit = Transmit {
+                dest: addr!("192.168.1.2:5000"),
+                data: vec![1; 100],
+                source: None,
+            };
+
+            assert_that!(is_fully_sent(&transmit, 100), is(true));
+            assert_that!(is_fully_sent(&transmit, 99), is(false));
+            assert_that!(is_fully_sent(&transmit, 0), is(false));
+        }
+    }
+
+    mod is_acceptable_peer {
+        use super::*;
+
+        #[test]
+        fn it_rejects_self_unless_configured_otherwise() {
+            let (our_pk, _) = gen_encrypt_keypair();
+            let peer = PeerInfo::new(addr!("192.168.1.100:1234"), our_pk);
+
+            let config = DiscoveryConfig::default();
+            assert_that!(is_acceptable_peer(&peer, &our_pk, &config), is(false));
+
+            let config = DiscoveryConfig {
+                include_self: true,
+                ..Default::default()
+            };
+            assert_that!(is_acceptable_peer(&peer, &our_pk, &config), is(true));
+        }
+
+        #[test]
+        fn it_rejects_peers_outside_allowed_subnets() {
+            let (our_pk, _) = gen_encrypt_keypair();
+            let (their_pk, _) = gen_encrypt_keypair();
+            let config = DiscoveryConfig {
+                allowed_subnets: vec![unwrap!("192.168.1.0/24".parse())],
+                ..Default::default()
+            };
+
+            let peer = PeerInfo::new(addr!("192.168.1.100:1234"), their_pk);
+            assert_that!(is_acceptable_peer(&peer, &our_pk, &config), is(true));
+
+            let peer = PeerInfo::new(addr!("10.0.0.100:1234"), their_pk);
+            assert_that!(is_acceptable_peer(&peer, &our_pk, &config), is(false));
+        }
+    }
+
+    fn ipv4_iface(name: &str, ip: Ipv4Addr, broadcast: Option<Ipv4Addr>) -> Interface {
+        Interface {
+            name: name.to_owned(),
+            addr: IfAddr::V4(Ifv4Addr {
+                ip,
+                netmask: ipv4!("255.255.255.0"),
+                broadcast,
+            }),
+        }
+    }
+
+    mod broadcast_targets {
+        use super::*;
+
+        #[cfg(target_os = "linux")]
+        #[test]
+        fn it_returns_virtual_lan_broadcast_address_of_its_interfaces() {
+            let mut lan = VirtualLan::new();
+            lan.add_interface("veth0", ipv4!("10.1.0.1"));
+            let mut ifaces = lan.interfaces();
+            ifaces.push(ipv4_iface("lo", ipv4!("127.0.0.1"), Some(LAN_BROADCAST)));
+
+            let targets = broadcast_targets(&ifaces, 5000);
+
+            assert_that!(
+                targets,
+                eq(vec![SocketAddr::V4(SocketAddrV4::new(LAN_BROADCAST, 5000))])
+            );
+        }
+
+        #[test]
+        fn it_returns_broadcast_addresses_of_ipv4_interfaces() {
+            let ifaces = vec![
+                ipv4_iface("eth0", ipv4!("192.168.1.5"), Some(ipv4!("192.168.1.255"))),
+                ipv4_iface("wlan0", ipv4!("10.0.0.5"), Some(ipv4!("10.0.0.255"))),
+            ];
+
+            let targets = broadcast_targets(&ifaces, 5000);
+
+            assert_that!(
+                targets,
+                eq(vec![addr!("192.168.1.255:5000"), addr!("10.0.0.255:5000")])
+            );
+        }
+
+        #[test]
+        fn it_skips_interfaces_without_broadcast_address() {
+            let ifaces = vec![
+                ipv4_iface("tun0", ipv4!("10.8.0.2"), None),
+                ipv4_iface("eth0", ipv4!("192.168.1.5"), Some(ipv4!("192.168.1.255"))),
+            ];
+
+            let targets = broadcast_targets(&ifaces, 5000);
+
+            assert_that!(targets, eq(vec![addr!("192.168.1.255:5000")]));
+        }
+
+        #[test]
+        fn it_skips_loopback_interfaces() {
+            let ifaces = vec![ipv4_iface(
+                "lo",
+                ipv4!("127.0.0.1"),
+                Some(ipv4!("127.255.255.255")),
+            )];
+
+            let targets = broadcast_targets(&ifaces, 5000);
+
+            assert_that!(&targets, empty());
+        }
+
+        #[test]
+        fn it_skips_ipv6_interfaces() {
+            let ifaces = vec![Interface {
+                name: "eth0".to_owned(),
+                addr: IfAddr::V6(Ifv6Addr {
+                    ip: ipv6!("fd00::5"),
+                    netmask: ipv6!("ffff:ffff:ffff:ffff::"),
+                    broadcast: Some(ipv6!("fd00::ffff:ffff:ffff:ffff")),
+                }),
+            }];
+
+            let targets = broadcast_targets(&ifaces, 5000);
+
+            assert_that!(&targets, empty());
+        }
+    }
+
+    /// Returns UDP port that is free at the moment.
+    fn free_port() -> u16 {
+        let sock = unwrap!(UdpSocket::bind(&addr!("0.0.0.0:0")));
+        unwrap!(sock.local_addr()).port()
+    }
+
+    mod discover_peers {
+        use super::*;
+
+        #[test]
+        fn when_same_node_is_server_and_client_it_filters_out_self() {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let task = unwrap!(discover_peers(
+                free_port(),
+                vec![addr!("192.168.1.100:1234")],
+                &our_pk,
+                &our_sk
+            )).collect()
+            .with_timeout(Duration::from_secs(10));
+            let peers = unwrap!(unwrap!(evloop.block_on(task)), "Peer discovery timed out");
+
+            assert_that!(&peers, empty());
+        }
+
+        #[test]
+        fn when_include_self_is_set_it_yields_self() {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let config = DiscoveryConfig {
+                include_self: true,
+                ..Default::default()
+            };
+            let task = unwrap!(discover_peers_with_config(
+                free_port(),
+                vec![addr!("192.168.1.100:1234")],
+                &our_pk,
+                &our_sk,
+                &config,
+            )).collect()
+            .with_timeout(Duration::from_secs(10));
+            let peers = unwrap!(unwrap!(evloop.block_on(task)), "Peer discovery timed out");
+
+            let expected_peer = PeerInfo::new(addr!("192.168.1.100:1234"), our_pk);
+            assert_that!(peers, eq(vec![vec![expected_peer]]));
+        }
+
+        #[cfg(target_os = "linux")]
+        #[test]
+        fn it_hears_its_own_broadcast_on_virtual_lan() {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let mut lan = VirtualLan::new();
+            lan.add_interface("veth0", ipv4!("10.1.0.1"));
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let config = DiscoveryConfig {
+                include_self: true,
+                ..lan.config()
+            };
+            let task = unwrap!(discover_peers_with_config(
+                lan.port(),
+                vec![addr!("10.1.0.1:1234")],
+                &our_pk,
+                &our_sk,
+                &config,
+            )).collect()
+            .with_timeout(Duration::from_secs(10));
+            let peers = unwrap!(unwrap!(evloop.block_on(task)), "Peer discovery timed out");
+
+            let expected_peer = PeerInfo::new(addr!("10.1.0.1:1234"), our_pk);
+            assert_that!(peers, eq(vec![vec![expected_peer]]));
+        }
+
+        #[test]
+        fn when_discovery_ends_server_socket_is_closed() {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let port = free_port();
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let mut discovery = unwrap!(discover_peers(
+                port,
+                vec![addr!("192.168.1.100:1234")],
+                &our_pk,
+                &our_sk
+            ));
+            let task = discovery
+                .by_ref()
+                .collect()
+                .with_timeout(Duration::from_secs(10));
+            let _peers = unwrap!(unwrap!(evloop.block_on(task)), "Peer discovery timed out");
+
+            let rebound =
+                UdpSocket::bind(&SocketAddr::V4(SocketAddrV4::new(ipv4!("0.0.0.0"), port)));
+            assert_that!(rebound.is_ok(), is(true));
+            drop(discovery);
+        }
+    }
+
+    mod discover_peers_cancellable {
+        use super::*;
+
+        #[test]
+        fn cancel_ends_stream_and_releases_server_port() {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let server_port = free_port();
+            let (peers, cancel) = unwrap!(discover_peers_cancellable(
+                server_port,
+                vec![addr!("192.168.1.100:1234")],
+                &our_pk,
+                &our_sk
+            ));
+
+            cancel.cancel();
+
+            let (peers, _stream) =
+                unwrap!(evloop.block_on(peers.into_future().map_err(|(e, _)| e)));
+            assert_that!(peers, none());
+            let sock = UdpSocket::bind(&SocketAddr::V4(SocketAddrV4::new(
+                ipv4!("0.0.0.0"),
+                server_port,
+            )));
+            assert_that!(sock.is_ok(), is(true));
+        }
+
+        #[test]
+        fn cancel_wakes_up_task_driving_discovery() {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let config = DiscoveryConfig {
+                loopback_ports: vec![free_port()],
+                ..Default::default()
+            };
+            let (peers, cancel) = unwrap!(discover_peers_cancellable_with_config(
+                0,
+                vec![addr!("192.168.1.100:1234")],
+                &our_pk,
+                &our_sk,
+                &config,
+            ));
+            let canceller = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                cancel.cancel();
+            });
+
+            let started = Instant::now();
+            let peers = unwrap!(evloop.block_on(peers.collect()));
+            unwrap!(canceller.join());
+
+            assert_that!(&peers, empty());
+            assert_that!(started.elapsed() < DEFAULT_RESPONSE_TIMEOUT, is(true));
+        }
+    }
+
+    mod discover_all {
+        use super::*;
+
+        fn merged(pub_key: PublicEncryptKey, addrs: Vec<SocketAddr>) -> MergedPeer {
+            MergedPeer {
+                pub_key,
+                addrs,
+                app_data: Vec::new(),
+            }
+        }
+
+        #[test]
+        fn it_merges_addresses_from_responses_of_the_same_peer() {
+            let (pk1, _) = gen_encrypt_keypair();
+            let (pk2, _) = gen_encrypt_keypair();
+            let peer1 = PeerInfo::new(addr!("192.168.1.100:1234"), pk1);
+            let peer1_v6 = PeerInfo::new(addr!("[fd00::1]:1234"), pk1);
+            let peer2 = PeerInfo::new(addr!("192.168.1.100:1234"), pk2);
+            let mut peers = Vec::new();
+
+            merge_peers(&mut peers, vec![peer1.clone()]);
+            merge_peers(&mut peers, vec![peer2, peer1_v6]);
+            merge_peers(&mut peers, vec![peer1]);
+
+            assert_that!(
+                peers,
+                eq(vec![
+                    merged(
+                        pk1,
+                        vec![addr!("192.168.1.100:1234"), addr!("[fd00::1]:1234")]
+                    ),
+                    merged(pk2, vec![addr!("192.168.1.100:1234")]),
+                ])
+            );
+        }
+
+        #[test]
+        fn it_returns_peer_reached_on_different_addresses_once() {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let (their_pk, _their_sk) = gen_encrypt_keypair();
+            let server1 = unwrap!(DiscoveryServer::new(
+                0,
+                vec![addr!("192.168.1.100:1234")],
+                &their_pk
+            ));
+            let server2 = unwrap!(DiscoveryServer::new(
+                0,
+                vec![addr!("10.0.0.100:1234")],
+                &their_pk
+            ));
+            let config = DiscoveryConfig {
+                loopback_ports: vec![server1.port(), server2.port()],
+                ..Default::default()
+            };
+
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let task = discover_all(0, &our_pk, &our_sk, &config)
+                .with_timeout(Duration::from_secs(10))
+                .map(|peers_opt| unwrap!(peers_opt, "Peer discovery timed out"))
+                .while_driving(server1.join(server2));
+
+            match evloop.block_on(task) {
+                Ok((mut peers, _servers_task)) => {
+                    assert_that!(peers.len(), eq(1));
+                    let mut peer = peers.remove(0);
+                    peer.addrs.sort();
+                    assert_that!(
+                        peer,
+                        eq(merged(
+                            their_pk,
+                            vec![addr!("10.0.0.100:1234"), addr!("192.168.1.100:1234")]
+                        ))
+                    );
+                }
+                _ => panic!("Peer discovery failed"),
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        #[test]
+        fn it_merges_peer_reached_via_multiple_interfaces() {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let mut lan = VirtualLan::new();
+            lan.add_interface("veth0", ipv4!("10.1.0.1"));
+            lan.add_interface("veth1", ipv4!("10.1.1.1"));
+            let (their_pk, _) = gen_encrypt_keypair();
+            lan.spawn_host(&mut evloop, vec![addr!("10.1.0.2:1234")], &their_pk);
+
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let task = discover_all(lan.port(), &our_pk, &our_sk, &lan.config())
+                .with_timeout(Duration::from_secs(10));
+            let peers = unwrap!(unwrap!(evloop.block_on(task)), "Peer discovery timed out");
+
+            assert_that!(
+                peers,
+                eq(vec![merged(their_pk, vec![addr!("10.1.0.2:1234")])])
+            );
+        }
+
+        #[test]
+        fn it_merges_peer_reached_via_broadcast_and_multicast() {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let (server_pk, _server_sk) = gen_encrypt_keypair();
+            let config = DiscoveryConfig {
+                multicast_group: Some(ipv4!("239.255.42.98")),
+                socket_options: SocketOptions {
+                    multicast_ttl: Some(2),
+                    multicast_loop: Some(true),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let server = unwrap!(DiscoveryServer::with_config(
+                0,
+                vec![addr!("192.168.1.100:1234")],
+                &server_pk,
+                &config,
+            ));
+            let server_port = server.port();
+
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let task = discover_all(server_port, &our_pk, &our_sk, &config)
+                .with_timeout(Duration::from_secs(10))
+                .map(|peers_opt| unwrap!(peers_opt, "Peer discovery timed out"))
+                .while_driving(server);
+
+            match evloop.block_on(task) {
+                Ok((their_addrs, _server_task)) => {
+                    assert_that!(
+                        their_addrs,
+                        eq(vec![merged(server_pk, vec![addr!("192.168.1.100:1234")])])
+                    );
+                }
+                _ => panic!("Peer discovery failed"),
+            }
+        }
+    }
+
+    mod multicast_membership {
+        use super::*;
+        #[cfg(target_os = "linux")]
+        use std::fs;
+
+        /// Lists groups joined on a given interface according to `/proc/net/igmp`.
+        #[cfg(target_os = "linux")]
+        fn joined_groups(iface_name: &str) -> Vec<Ipv4Addr> {
+            let igmp = unwrap!(fs::read_to_string("/proc/net/igmp"));
+            let mut device = String::new();
+            let mut groups = Vec::new();
+            for line in igmp.lines().skip(1) {
+                let mut fields = line.split_whitespace();
+                if line.starts_with(char::is_whitespace) {
+                    // kernel prints groups the same way as addresses in /proc/net/route
+                    let group = unwrap!(u32::from_str_radix(unwrap!(fields.next()), 16));
+                    if device == iface_name {
+                        groups.push(Ipv4Addr::from(group.to_ne_bytes()));
+                    }
+                } else {
+                    device = unwrap!(fields.nth(1)).to_owned();
+                }
+            }
+            groups
+        }
+
+        fn loopback_config(group: Ipv4Addr) -> DiscoveryConfig {
+            DiscoveryConfig {
+                multicast_group: Some(group),
+                interfaces: InterfaceSource::new(|| {
+                    Ok(vec![
+                        ipv4_iface("lo", ipv4!("127.0.0.1"), None),
+                        // doesn't exist, can't be joined on
+                        ipv4_iface("veth99", ipv4!("10.99.0.1"), None),
+                    ])
+                }),
+                ..Default::default()
+            }
+        }
+
+        #[test]
+        fn without_group_nothing_is_joined() {
+            let sock = unwrap!(UdpSocket::bind(&addr!("0.0.0.0:0")));
+
+            let membership = unwrap!(MulticastMembership::join(&sock, &Default::default()));
+
+            assert_that!(membership.is_none(), is(true));
+        }
+
+        #[cfg(target_os = "linux")]
+        #[test]
+        fn it_joins_and_leaves_group_on_every_allowed_interface() {
+            let group = ipv4!("239.255.42.101");
+            let sock = unwrap!(UdpSocket::bind(&addr!("0.0.0.0:0")));
+
+            let membership = unwrap!(unwrap!(MulticastMembership::join(
+                &sock,
+                &loopback_config(group)
+            )));
+            assert_that!(membership.iface_ips.clone(), eq(vec![ipv4!("127.0.0.1")]));
+            assert_that!(joined_groups("lo").contains(&group), is(true));
+
+            membership.leave(&sock);
+            assert_that!(joined_groups("lo").contains(&group), is(false));
+        }
+
+        #[test]
+        fn when_no_interface_is_allowed_it_joins_on_default_interface() {
+            let sock = unwrap!(UdpSocket::bind(&addr!("0.0.0.0:0")));
+            let config = DiscoveryConfig {
+                allowed_subnets: vec![unwrap!("10.1.0.0/16".parse())],
+                ..loopback_config(ipv4!("239.255.42.102"))
+            };
+
+            let membership = unwrap!(unwrap!(MulticastMembership::join(&sock, &config)));
+
+            assert_that!(membership.iface_ips.clone(), eq(vec![ipv4!("0.0.0.0")]));
+        }
+
+        #[cfg(target_os = "linux")]
+        #[test]
+        fn server_leaves_group_when_dropped() {
+            let group = ipv4!("239.255.42.103");
+            let (server_pk, _server_sk) = gen_encrypt_keypair();
+            let server = unwrap!(DiscoveryServer::with_config(
+                0,
+                vec![addr!("192.168.1.100:1234")],
+                &server_pk,
+                &loopback_config(group),
+            ));
+            assert_that!(joined_groups("lo").contains(&group), is(true));
+
+            drop(server);
+
+            assert_that!(joined_groups("lo").contains(&group), is(false));
+        }
+    }
+
+    mod discovery_targets {
+        use super::*;
+
+        #[cfg(target_os = "linux")]
+        #[test]
+        fn it_lists_broadcast_address_of_every_given_interface() {
+            let mut lan = VirtualLan::new();
+            lan.add_interface("veth0", ipv4!("10.1.0.1"));
+            let mut ifaces = lan.interfaces();
+            ifaces.push(ipv4_iface("lo", ipv4!("127.0.0.1"), None));
+            let config = DiscoveryConfig {
+                interfaces: InterfaceSource::new(move || Ok(ifaces.clone())),
+                ..Default::default()
+            };
+
+            let targets = unwrap!(discovery_targets(5000, &config));
+
+            assert_that!(targets.len(), eq(1));
+            assert_that!(
+                targets[0].addr,
+                eq(SocketAddr::V4(SocketAddrV4::new(LAN_BROADCAST, 5000)))
+            );
+            assert_that!(targets[0].socket_error.is_none(), is(true));
+        }
+
+        #[cfg(target_os = "linux")]
+        #[test]
+        fn it_reports_targets_socket_can_not_be_configured_for() {
+            let mut lan = VirtualLan::new();
+            lan.add_interface("veth0", ipv4!("10.1.0.1"));
+            let config = DiscoveryConfig {
+                socket_options: SocketOptions {
+                    ttl: Some(256),
+                    ..Default::default()
+                },
+                ..lan.config()
+            };
+
+            let targets = unwrap!(discovery_targets(5000, &config));
+
+            assert_that!(targets.len(), eq(1));
+            assert_that!(targets[0].socket_error.is_some(), is(true));
+        }
+
+        #[test]
+        fn by_default_it_lists_broadcast_addresses_of_system_interfaces() {
+            let system_broadcasts: Vec<_> = unwrap!(get_if_addrs())
+                .into_iter()
+                .filter(|iface| !iface.is_loopback())
+                .filter_map(|iface| match iface.addr {
+                    IfAddr::V4(ref addr) => addr.broadcast,
+                    IfAddr::V6(_) => None,
+                }).collect();
+
+            let targets = unwrap!(discovery_targets(5000, &DiscoveryConfig::default()));
+
+            let addrs: Vec<_> = targets.iter().map(|target| target.addr).collect();
+            for ip in system_broadcasts {
+                assert_that!(
+                    &addrs,
+                    contains(SocketAddr::V4(SocketAddrV4::new(ip, 5000)))
+                );
+            }
+            for addr in addrs {
+                assert_that!(addr.port(), eq(5000));
+                assert_that!(addr.ip().is_loopback(), is(false));
+            }
+        }
+    }
+
+    mod decrypt_response {
+        use super::*;
+
+        #[test]
+        fn when_response_can_not_be_decrypted_it_returns_decrypt_error() {
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+
+            match decrypt_response(&[1, 2, 3], &our_pk, &our_sk) {
+                Err(DiscoveryError::DecryptFailed(_)) => (),
+                res => panic!("Unexpected result: {:?}", res),
+            }
+        }
+
+        #[test]
+        fn when_decrypted_response_is_malformed_it_returns_deserialize_error() {
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let malformed = our_pk.anonymously_encrypt_bytes(&[0xff; 8]);
+
+            match decrypt_response(&malformed, &our_pk, &our_sk) {
+                Err(DiscoveryError::DeserializeFailed(_)) => (),
+                res => panic!("Unexpected result: {:?}", res),
+            }
+        }
+
+        #[test]
+        fn when_decrypted_message_is_not_response_it_returns_invalid_response_error() {
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let request = unwrap!(DiscoveryMsg::serialized_request(our_pk));
+            let not_response = our_pk.anonymously_encrypt_bytes(&request);
+
+            match decrypt_response(&not_response, &our_pk, &our_sk) {
+                Err(DiscoveryError::InvalidResponse) => (),
+                res => panic!("Unexpected result: {:?}", res),
+            }
+        }
+    }
+
+    mod shout_for_peers {
+        use super::*;
+
+        #[test]
+        fn it_broadcasts_requests_on_lan_and_collects_peer_addresses() {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let (server_pk, _server_sk) = gen_encrypt_keypair();
+            let server = unwrap!(DiscoveryServer::new(
+                0,
+                vec![addr!("192.168.1.100:1234"), addr!("127.0.0.1:1234")],
+                &server_pk,
+            ));
+            let server_port = server.port();
+
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let task = shout_for_peers(server_port, &our_pk, &our_sk)
+                .collect()
+                .with_timeout(Duration::from_secs(10))
+                .map(|addrs_opt| unwrap!(addrs_opt, "Peer discovery timed out"))
+                .while_driving(server);
+
+            match evloop.block_on(task) {
+                Ok((their_addrs, _server_task)) => {
+                    assert_that!(
+                        their_addrs,
+                        eq(vec![vec![
+                            PeerInfo::new(addr!("192.168.1.100:1234"), server_pk),
+                            PeerInfo::new(addr!("127.0.0.1:1234"), server_pk),
+                        ]])
+                    );
+                }
+                _ => panic!("Peer discovery failed"),
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        #[test]
+        fn it_discovers_host_on_virtual_lan() {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let mut lan = VirtualLan::new();
+            lan.add_interface("veth0", ipv4!("10.1.0.1"));
+            let (their_pk, _) = gen_encrypt_keypair();
+            lan.spawn_host(&mut evloop, vec![addr!("10.1.0.2:1234")], &their_pk);
+
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let task = shout_for_peers_with_config(lan.port(), &our_pk, &our_sk, &lan.config())
+                .collect()
+                .with_timeout(Duration::from_secs(10));
+            let peers = unwrap!(unwrap!(evloop.block_on(task)), "Peer discovery timed out");
+
+            assert_that!(
+                peers,
+                eq(vec![vec![PeerInfo::new(addr!("10.1.0.2:1234"), their_pk)]])
+            );
+        }
+
+        #[cfg(target_os = "linux")]
+        #[test]
+        fn it_skips_host_addresses_outside_allowed_subnets() {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let mut lan = VirtualLan::new();
+            lan.add_interface("veth0", ipv4!("10.1.0.1"));
+            let (their_pk, _) = gen_encrypt_keypair();
+            lan.spawn_host(
+                &mut evloop,
+                vec![addr!("10.1.0.2:1234"), addr!("10.2.0.2:1234")],
+                &their_pk,
+            );
+
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let config = DiscoveryConfig {
+                // virtual LAN broadcasts within 127.0.0.0/8
+                allowed_subnets: vec![
+                    unwrap!("10.1.0.0/16".parse()),
+                    unwrap!("127.0.0.0/8".parse()),
+                ],
+                ..lan.config()
+            };
+            let task = shout_for_peers_with_config(lan.port(), &our_pk, &our_sk, &config)
+                .collect()
+                .with_timeout(Duration::from_secs(10));
+            let peers = unwrap!(unwrap!(evloop.block_on(task)), "Peer discovery timed out");
+
+            assert_that!(
+                peers,
+                eq(vec![vec![PeerInfo::new(addr!("10.1.0.2:1234"), their_pk)]])
+            );
+        }
+
+        #[cfg(target_os = "linux")]
+        #[test]
+        fn it_does_not_reach_hosts_of_other_lans() {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let mut lan = VirtualLan::new();
+            lan.add_interface("veth0", ipv4!("10.1.0.1"));
+            let other_lan = VirtualLan::new();
+            let (their_pk, _) = gen_encrypt_keypair();
+            other_lan.spawn_host(&mut evloop, vec![addr!("10.2.0.2:1234")], &their_pk);
+
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let task = shout_for_peers_with_config(lan.port(), &our_pk, &our_sk, &lan.config())
+                .collect()
+                .with_timeout(Duration::from_secs(10));
+            let peers = unwrap!(unwrap!(evloop.block_on(task)), "Peer discovery timed out");
+
+            assert_that!(&peers, empty());
+        }
+
+        #[test]
+        fn it_measures_response_round_trip_time() {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let (server_pk, _server_sk) = gen_encrypt_keypair();
+            let server = unwrap!(DiscoveryServer::new(
+                0,
+                vec![addr!("192.168.1.100:1234")],
+                &server_pk,
+            ));
+            let server_port = server.port();
+
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let started_at = Instant::now();
+            let task = shout_for_peers_with_rtt(server_port, &our_pk, &our_sk, &Default::default())
+                .collect()
+                .with_timeout(Duration::from_secs(10))
+                .map(|discovered_opt| unwrap!(discovered_opt, "Peer discovery timed out"))
+                .while_driving(server);
+
+            match evloop.block_on(task) {
+                Ok((discovered, _server_task)) => {
+                    assert_that!(discovered.len(), eq(1));
+                    assert_that!(discovered[0].rtt, greater_than(Duration::from_secs(0)));
+                    assert_that!(discovered[0].rtt, less_than(started_at.elapsed()));
+                }
+                _ => panic!("Peer discovery failed"),
+            }
+        }
+
+        #[test]
+        fn it_yields_peers_with_application_data() {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let (server_pk, _server_sk) = gen_encrypt_keypair();
+            let server = unwrap!(DiscoveryServer::with_app_data(
+                0,
+                vec![addr!("192.168.1.100:1234")],
+                &server_pk,
+                b"v1.0".to_vec(),
             ));
             let server_port = server.port();
 
@@ -305,20 +2929,482 @@ mod tests {
                 .map(|addrs_opt| unwrap!(addrs_opt, "Peer discovery timed out"))
                 .while_driving(server);
 
+            let expected_peer = PeerInfo::new(addr!("192.168.1.100:1234"), server_pk)
+                .with_app_data(b"v1.0".to_vec());
+            match evloop.block_on(task) {
+                Ok((their_addrs, _server_task)) => {
+                    assert_that!(their_addrs, eq(vec![vec![expected_peer]]));
+                }
+                _ => panic!("Peer discovery failed"),
+            }
+        }
+
+        /// Shouts to 3 targets, each answering after 300ms and awaited for 500ms, and returns how
+        /// long it took.
+        fn time_delayed_shout(max_concurrent_requests: usize) -> Duration {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let (server_pk, _server_sk) = gen_encrypt_keypair();
+            let server = unwrap!(MockDiscoveryServer::new(
+                0,
+                server_pk,
+                MockBehavior::Delay(
+                    Duration::from_millis(300),
+                    vec![addr!("192.168.1.100:1234")]
+                ),
+            ));
+            let server_addr = SocketAddr::V4(SocketAddrV4::new(ipv4!("127.0.0.1"), server.port()));
+
+            let config = DiscoveryConfig {
+                max_concurrent_requests,
+                response_timeout: Duration::from_millis(500),
+                ..Default::default()
+            };
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let started = Instant::now();
+            let task = shout_to(vec![server_addr; 3], &our_pk, &our_sk, &config)
+                .collect()
+                .with_timeout(Duration::from_secs(10))
+                .map(|addrs_opt| unwrap!(addrs_opt, "Peer discovery timed out"))
+                .while_driving(server);
+
+            match evloop.block_on(task) {
+                Ok((their_addrs, _server_task)) => assert_that!(their_addrs.len(), eq(3)),
+                _ => panic!("Peer discovery failed"),
+            }
+            started.elapsed()
+        }
+
+        #[test]
+        fn discoveries_sharing_rate_limiter_respect_it_together() {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let (server_pk, _server_sk) = gen_encrypt_keypair();
+            let server = unwrap!(MockDiscoveryServer::new(
+                0,
+                server_pk,
+                MockBehavior::Respond(vec![addr!("192.168.1.100:1234")]),
+            ));
+            let server_addr = SocketAddr::V4(SocketAddrV4::new(ipv4!("127.0.0.1"), server.port()));
+            let config = DiscoveryConfig {
+                rate_limiter: Some(RateLimiter::new(2, Duration::from_millis(600))),
+                ..Default::default()
+            };
+
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let started = Instant::now();
+            let shout1 = shout_to(vec![server_addr; 2], &our_pk, &our_sk, &config).collect();
+            let shout2 = shout_to(vec![server_addr; 2], &our_pk, &our_sk, &config).collect();
+            let task = shout1
+                .join(shout2)
+                .with_timeout(Duration::from_secs(10))
+                .map(|res_opt| unwrap!(res_opt, "Peer discovery timed out"))
+                .while_driving(server);
+
+            match evloop.block_on(task) {
+                Ok(((found1, found2), _server_task)) => {
+                    assert_that!(found1.len() + found2.len(), eq(4));
+                }
+                _ => panic!("Peer discovery failed"),
+            }
+            // 2 requests right away, 3rd after 300ms, 4th after 600ms
+            assert_that!(started.elapsed(), greater_than(Duration::from_millis(550)));
+        }
+
+        #[test]
+        fn it_collects_every_response_to_the_same_request() {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let responder = unwrap!(std::net::UdpSocket::bind("127.0.0.1:0"));
+            let target = unwrap!(responder.local_addr());
+            let (pk1, _) = gen_encrypt_keypair();
+            let (pk2, _) = gen_encrypt_keypair();
+            // answers like two peers reached by the same broadcast request would
+            let responders = thread::spawn(move || {
+                let mut buf = vec![0; 65000];
+                let (bytes_read, requester) = unwrap!(responder.recv_from(&mut buf));
+                let their_pk = match unwrap!(DiscoveryMsg::deserialize(&buf[..bytes_read])) {
+                    DiscoveryMsg::Request(their_pk) => their_pk,
+                    _ => panic!("Expected discovery request"),
+                };
+                for (pub_key, addr) in vec![
+                    (pk1, addr!("192.168.1.100:1234")),
+                    (pk2, addr!("192.168.1.101:1234")),
+                ] {
+                    let resp = DiscoveryResponse {
+                        pub_key,
+                        addrs: vec![addr],
+                        app_data: Vec::new(),
+                    };
+                    let packet = unwrap!(DiscoveryMsg::encrypted_response(&their_pk, resp));
+                    let sock = unwrap!(std::net::UdpSocket::bind("127.0.0.1:0"));
+                    let _ = unwrap!(sock.send_to(&packet, requester));
+                }
+            });
+
+            let config = DiscoveryConfig {
+                response_timeout: Duration::from_secs(1),
+                ..Default::default()
+            };
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let task = shout_to(vec![target], &our_pk, &our_sk, &config)
+                .map(|discovered| discovered.peers)
+                .collect()
+                .with_timeout(Duration::from_secs(10));
+            let peers = unwrap!(unwrap!(evloop.block_on(task)), "Peer discovery timed out");
+            unwrap!(responders.join());
+
+            assert_that!(
+                peers,
+                eq(vec![
+                    vec![PeerInfo::new(addr!("192.168.1.100:1234"), pk1)],
+                    vec![PeerInfo::new(addr!("192.168.1.101:1234"), pk2)],
+                ])
+            );
+        }
+
+        #[test]
+        fn it_sends_requests_concurrently() {
+            assert_that!(
+                time_delayed_shout(DEFAULT_MAX_CONCURRENT_REQUESTS),
+                less_than(Duration::from_millis(900))
+            );
+        }
+
+        #[test]
+        fn it_does_not_exceed_max_concurrent_requests() {
+            assert_that!(
+                time_delayed_shout(1),
+                greater_than_or_equal_to(Duration::from_millis(900))
+            );
+        }
+
+        #[test]
+        fn it_sends_requests_to_multicast_group() {
+            let config = DiscoveryConfig {
+                multicast_group: Some(ipv4!("239.255.42.99")),
+                allowed_subnets: vec![unwrap!("10.0.0.0/8".parse())],
+                ..Default::default()
+            };
+
+            let targets = unwrap!(target_addrs(5000, &config));
+
+            assert_that!(&targets, contains(vec![addr!("239.255.42.99:5000")]));
+        }
+
+        #[test]
+        fn it_sends_requests_to_broadcast_addresses_of_allowed_subnets() {
+            let config = DiscoveryConfig {
+                allowed_subnets: vec![unwrap!("192.168.0.0/16".parse())],
+                interfaces: InterfaceSource::new(|| {
+                    Ok(vec![
+                        ipv4_iface("wlan0", ipv4!("192.168.1.5"), Some(ipv4!("192.168.1.255"))),
+                        ipv4_iface("eth0", ipv4!("10.0.0.5"), Some(ipv4!("10.0.0.255"))),
+                    ])
+                }),
+                ..Default::default()
+            };
+
+            let targets = unwrap!(target_addrs(5000, &config));
+
+            assert_that!(targets, eq(vec![addr!("192.168.1.255:5000")]));
+        }
+
+        #[test]
+        fn when_interfaces_can_not_be_listed_it_fails() {
+            let config = DiscoveryConfig {
+                interfaces: InterfaceSource::new(|| Err(io::ErrorKind::NotFound.into())),
+                ..Default::default()
+            };
+
+            let res = target_addrs(5000, &config);
+
+            assert_that!(res.is_err(), is(true));
+        }
+
+        #[test]
+        fn in_loopback_mode_it_sends_requests_only_to_loopback_ports() {
+            let config = DiscoveryConfig {
+                multicast_group: Some(ipv4!("239.255.42.99")),
+                loopback_ports: vec![5001, 5002],
+                ..Default::default()
+            };
+
+            let targets = unwrap!(target_addrs(5000, &config));
+
+            assert_that!(
+                targets,
+                eq(vec![addr!("127.0.0.1:5001"), addr!("127.0.0.1:5002")])
+            );
+        }
+
+        #[test]
+        fn in_loopback_mode_it_discovers_servers_on_the_same_host() {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let (server1_pk, _) = gen_encrypt_keypair();
+            let server1 = unwrap!(DiscoveryServer::new(
+                0,
+                vec![addr!("127.0.0.1:1234")],
+                &server1_pk,
+            ));
+            let (server2_pk, _) = gen_encrypt_keypair();
+            let server2 = unwrap!(DiscoveryServer::new(
+                0,
+                vec![addr!("127.0.0.1:1235")],
+                &server2_pk,
+            ));
+            let config = DiscoveryConfig {
+                loopback_ports: vec![server1.port(), server2.port()],
+                ..Default::default()
+            };
+
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let task = shout_for_peers_with_config(0, &our_pk, &our_sk, &config)
+                .collect()
+                .with_timeout(Duration::from_secs(10))
+                .map(|addrs_opt| unwrap!(addrs_opt, "Peer discovery timed out"))
+                .while_driving(server1.join(server2));
+
+            match evloop.block_on(task) {
+                Ok((their_addrs, _servers_task)) => {
+                    let their_addrs: Vec<_> = their_addrs.into_iter().flatten().collect();
+                    assert_that!(their_addrs.len(), eq(2));
+                    assert_that!(
+                        &their_addrs,
+                        contains(vec![
+                            PeerInfo::new(addr!("127.0.0.1:1234"), server1_pk),
+                            PeerInfo::new(addr!("127.0.0.1:1235"), server2_pk),
+                        ])
+                    );
+                }
+                _ => panic!("Peer discovery failed"),
+            }
+        }
+
+        #[test]
+        fn it_discovers_server_that_joined_multicast_group() {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let group = ipv4!("239.255.42.99");
+            let (server_pk, _server_sk) = gen_encrypt_keypair();
+            let config = DiscoveryConfig {
+                multicast_group: Some(group),
+                socket_options: SocketOptions {
+                    multicast_ttl: Some(2),
+                    multicast_loop: Some(true),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let server = unwrap!(DiscoveryServer::with_config(
+                0,
+                vec![addr!("192.168.1.100:1234")],
+                &server_pk,
+                &config,
+            ));
+            let group_addr = SocketAddr::V4(SocketAddrV4::new(group, server.port()));
+
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let task = shout_to(vec![group_addr], &our_pk, &our_sk, &config)
+                .map(|discovered| discovered.peers)
+                .collect()
+                .with_timeout(Duration::from_secs(10))
+                .map(|addrs_opt| unwrap!(addrs_opt, "Peer discovery timed out"))
+                .while_driving(server);
+
             match evloop.block_on(task) {
                 Ok((their_addrs, _server_task)) => {
                     assert_that!(
                         their_addrs,
-                        eq(vec![vec![
-                            PeerInfo::new(addr!("192.168.1.100:1234"), server_pk),
-                            PeerInfo::new(addr!("127.0.0.1:1234"), server_pk),
-                        ]])
+                        eq(vec![vec![PeerInfo::new(
+                            addr!("192.168.1.100:1234"),
+                            server_pk
+                        )]])
                     );
                 }
                 _ => panic!("Peer discovery failed"),
             }
         }
 
+        #[cfg(feature = "wire-tap")]
+        #[test]
+        fn it_passes_raw_datagrams_to_wire_tap() {
+            use std::sync::Mutex;
+
+            let mut evloop = unwrap!(Runtime::new());
+
+            let (server_pk, _server_sk) = gen_encrypt_keypair();
+            let server = unwrap!(DiscoveryServer::new(
+                0,
+                vec![addr!("192.168.1.100:1234")],
+                &server_pk,
+            ));
+            let server_port = server.port();
+
+            let captured = Arc::new(Mutex::new(Vec::new()));
+            let captured2 = captured.clone();
+            let config = DiscoveryConfig {
+                wire_tap: Some(WireTap::new(move |direction, _addr, bytes| {
+                    unwrap!(captured2.lock()).push((direction, bytes.to_vec()))
+                })),
+                ..Default::default()
+            };
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let task = shout_for_peers_with_config(server_port, &our_pk, &our_sk, &config)
+                .collect()
+                .with_timeout(Duration::from_secs(10))
+                .map(|addrs_opt| unwrap!(addrs_opt, "Peer discovery timed out"))
+                .while_driving(server);
+            let _ = evloop.block_on(task);
+
+            let request = unwrap!(DiscoveryMsg::serialized_request(our_pk));
+            let captured = unwrap!(captured.lock());
+            let expected_request = (Direction::Sent, request);
+            assert_that!(&captured[0], eq(&expected_request));
+            let responses = captured
+                .iter()
+                .filter(|&&(direction, _)| direction == Direction::Received);
+            for (_, resp) in responses {
+                let plaintext = unwrap!(our_sk.anonymously_decrypt_bytes(resp, &our_pk));
+                assert_that!(DiscoveryMsg::deserialize(&plaintext).is_ok(), is(true));
+            }
+        }
+
+        #[test]
+        fn it_waits_for_delayed_responses() {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let (server_pk, _server_sk) = gen_encrypt_keypair();
+            let server = unwrap!(MockDiscoveryServer::new(
+                0,
+                server_pk,
+                MockBehavior::Delay(
+                    Duration::from_millis(500),
+                    vec![addr!("192.168.1.100:1234")]
+                ),
+            ));
+            let server_port = server.port();
+
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let task = shout_for_peers(server_port, &our_pk, &our_sk)
+                .collect()
+                .with_timeout(Duration::from_secs(10))
+                .map(|addrs_opt| unwrap!(addrs_opt, "Peer discovery timed out"))
+                .while_driving(server);
+
+            let expected_peer = PeerInfo::new(addr!("192.168.1.100:1234"), server_pk);
+            match evloop.block_on(task) {
+                Ok((their_addrs, _server_task)) => {
+                    assert_that!(their_addrs, eq(vec![vec![expected_peer]]));
+                }
+                _ => panic!("Peer discovery failed"),
+            }
+        }
+
+        #[test]
+        fn when_peer_does_not_respond_it_ends_without_peers() {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let (server_pk, _server_sk) = gen_encrypt_keypair();
+            let server = unwrap!(MockDiscoveryServer::new(0, server_pk, MockBehavior::Ignore));
+            let server_port = server.port();
+
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let task = shout_for_peers(server_port, &our_pk, &our_sk)
+                .collect()
+                .with_timeout(Duration::from_secs(10))
+                .map(|addrs_opt| unwrap!(addrs_opt, "Peer discovery timed out"))
+                .while_driving(server);
+
+            match evloop.block_on(task) {
+                Ok((their_addrs, _server_task)) => assert_that!(&their_addrs, empty()),
+                _ => panic!("Peer discovery failed"),
+            }
+        }
+
+        /// Shouts to the servers mocking given behaviors plus one that responds properly and
+        /// checks that only the latter is discovered.
+        fn assert_only_good_server_is_discovered(
+            our_pk: &PublicEncryptKey,
+            our_sk: &SecretEncryptKey,
+            bad_behavior: MockBehavior,
+        ) {
+            let mut evloop = unwrap!(Runtime::new());
+
+            let (bad_pk, _bad_sk) = gen_encrypt_keypair();
+            let bad_server = unwrap!(MockDiscoveryServer::new(0, bad_pk, bad_behavior));
+            let (good_pk, _good_sk) = gen_encrypt_keypair();
+            let good_server = unwrap!(MockDiscoveryServer::new(
+                0,
+                good_pk,
+                MockBehavior::Respond(vec![addr!("192.168.1.100:1234")]),
+            ));
+            let targets = vec![
+                SocketAddr::V4(SocketAddrV4::new(ipv4!("127.0.0.1"), bad_server.port())),
+                SocketAddr::V4(SocketAddrV4::new(ipv4!("127.0.0.1"), good_server.port())),
+            ];
+            let config = DiscoveryConfig {
+                response_timeout: Duration::from_millis(500),
+                ..Default::default()
+            };
+
+            let task = shout_to(targets, our_pk, our_sk, &config)
+                .map(|discovered| discovered.peers)
+                .collect()
+                .with_timeout(Duration::from_secs(10))
+                .map(|addrs_opt| unwrap!(addrs_opt, "Peer discovery timed out"))
+                .while_driving(bad_server)
+                .while_driving(good_server);
+
+            match evloop.block_on(task) {
+                Ok(((their_addrs, _bad_server), _good_server)) => assert_that!(
+                    their_addrs,
+                    eq(vec![vec![PeerInfo::new(
+                        addr!("192.168.1.100:1234"),
+                        good_pk
+                    )]])
+                ),
+                _ => panic!("Peer discovery failed"),
+            }
+        }
+
+        #[test]
+        fn it_drops_responses_that_can_not_be_decrypted() {
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+
+            assert_only_good_server_is_discovered(
+                &our_pk,
+                &our_sk,
+                MockBehavior::Malformed(vec![1, 2, 3]),
+            );
+        }
+
+        #[test]
+        fn it_drops_decrypted_responses_that_are_malformed() {
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let malformed = our_pk.anonymously_encrypt_bytes(&[0xff; 8]);
+
+            assert_only_good_server_is_discovered(
+                &our_pk,
+                &our_sk,
+                MockBehavior::Malformed(malformed),
+            );
+        }
+
+        #[test]
+        fn it_drops_decrypted_messages_that_are_not_responses() {
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let request = unwrap!(DiscoveryMsg::serialized_request(our_pk));
+            let not_response = our_pk.anonymously_encrypt_bytes(&request);
+
+            assert_only_good_server_is_discovered(
+                &our_pk,
+                &our_sk,
+                MockBehavior::Malformed(not_response),
+            );
+        }
+
         #[test]
         fn it_filters_responses_from_self() {
             let mut evloop = unwrap!(Runtime::new());
@@ -0,0 +1,241 @@
+//! Lets discovery server bound to `0.0.0.0` answer from the local address a request arrived on:
+//! datagrams are received together with that address and responses are sent from it with
+//! `IP_PKTINFO`. Hosts with multiple addresses on the same interface would otherwise answer from
+//! whichever address OS picks for the route back, which the requester might not expect.
+//!
+//! Only Linux and Android are supported, elsewhere local address is unknown and OS picks the
+//! source address as usual.
+
+use priv_prelude::*;
+use std::io;
+use std::net::IpAddr;
+use tokio::net::UdpSocket;
+
+/// Makes socket report local address of every received datagram. Does nothing where that's not
+/// supported.
+pub fn enable(sock: &UdpSocket) -> io::Result<()> {
+    sys::enable(sock)
+}
+
+/// Receives datagram from a socket. Besides the sender, returns the local address datagram was
+/// sent to, if it's known: `enable()` was called and the platform supports it.
+pub fn poll_recv_from(
+    sock: &mut UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<Async<(usize, SocketAddr, Option<IpAddr>)>> {
+    sys::poll_recv_from(sock, buf)
+}
+
+/// Sends datagram from a given local IPv4 address, if it's given and the platform supports it.
+/// Otherwise OS picks the source address.
+pub fn poll_send_from(
+    sock: &mut UdpSocket,
+    buf: &[u8],
+    dest: &SocketAddr,
+    source: Option<IpAddr>,
+) -> io::Result<Async<usize>> {
+    sys::poll_send_from(sock, buf, dest, source)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sys {
+    use libc;
+    use priv_prelude::*;
+    use std::io;
+    use std::mem;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::ptr;
+    use tokio::net::UdpSocket;
+
+    /// Ancillary data buffer, big enough for `in_pktinfo` and aligned like `cmsghdr`.
+    type ControlBuf = [u64; 8];
+
+    pub fn enable(sock: &UdpSocket) -> io::Result<()> {
+        let on: i32 = 1;
+        let res = unsafe {
+            libc::setsockopt(
+                sock.as_raw_fd(),
+                libc::IPPROTO_IP,
+                libc::IP_PKTINFO,
+                &on as *const _ as *const libc::c_void,
+                mem::size_of_val(&on) as libc::socklen_t,
+            )
+        };
+        if res == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    pub fn poll_recv_from(
+        sock: &mut UdpSocket,
+        buf: &mut [u8],
+    ) -> io::Result<Async<(usize, SocketAddr, Option<IpAddr>)>> {
+        match recv_msg(sock.as_raw_fd(), buf) {
+            Ok((bytes_read, sender_addr, local_ip)) => Ok(Async::Ready((
+                bytes_read,
+                SocketAddr::V4(sender_addr),
+                local_ip.map(IpAddr::V4),
+            ))),
+            // tokio socket only clears its readiness and registers the task for wake up on its
+            // own receive calls. Should a datagram arrive in the meantime, it's returned without
+            // the local address.
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                match sock.poll_recv_from(buf)? {
+                    Async::Ready((bytes_read, sender_addr)) => {
+                        Ok(Async::Ready((bytes_read, sender_addr, None)))
+                    }
+                    Async::NotReady => Ok(Async::NotReady),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn poll_send_from(
+        sock: &mut UdpSocket,
+        buf: &[u8],
+        dest: &SocketAddr,
+        source: Option<IpAddr>,
+    ) -> io::Result<Async<usize>> {
+        let (dest_v4, source) = match (dest, source) {
+            (SocketAddr::V4(dest_v4), Some(IpAddr::V4(source))) => (dest_v4, source),
+            _ => return sock.poll_send_to(buf, dest),
+        };
+        match send_msg(sock.as_raw_fd(), buf, dest_v4, source) {
+            Ok(bytes_sent) => Ok(Async::Ready(bytes_sent)),
+            // same as with receiving: when socket becomes writable before tokio gets to it, the
+            // datagram is sent from whatever address OS picks
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => sock.poll_send_to(buf, dest),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn recv_msg(fd: RawFd, buf: &mut [u8]) -> io::Result<(usize, SocketAddrV4, Option<Ipv4Addr>)> {
+        let mut sender: libc::sockaddr_in = unsafe { mem::zeroed() };
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut control: ControlBuf = [0; 8];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut sender as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of::<ControlBuf>() as _;
+
+        let bytes_read = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+        if bytes_read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if i32::from(sender.sin_family) != libc::AF_INET {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Datagram sender is not an IPv4 address",
+            ));
+        }
+
+        let mut local_ip = None;
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        while !cmsg.is_null() {
+            let hdr = unsafe { &*cmsg };
+            if hdr.cmsg_level == libc::IPPROTO_IP && hdr.cmsg_type == libc::IP_PKTINFO {
+                let info = unsafe {
+                    ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::in_pktinfo)
+                };
+                local_ip = Some(ipv4_from(info.ipi_spec_dst));
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+        let sender_addr =
+            SocketAddrV4::new(ipv4_from(sender.sin_addr), u16::from_be(sender.sin_port));
+        Ok((bytes_read as usize, sender_addr, local_ip))
+    }
+
+    fn send_msg(fd: RawFd, buf: &[u8], dest: &SocketAddrV4, source: Ipv4Addr) -> io::Result<usize> {
+        let mut dest_addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+        dest_addr.sin_family = libc::AF_INET as libc::sa_family_t;
+        dest_addr.sin_port = dest.port().to_be();
+        dest_addr.sin_addr = in_addr_from(*dest.ip());
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut control: ControlBuf = [0; 8];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut dest_addr as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        let info_len = mem::size_of::<libc::in_pktinfo>() as u32;
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(info_len) } as _;
+
+        let info = libc::in_pktinfo {
+            ipi_ifindex: 0,
+            ipi_spec_dst: in_addr_from(source),
+            ipi_addr: in_addr_from(ipv4!("0.0.0.0")),
+        };
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::IPPROTO_IP;
+            (*cmsg).cmsg_type = libc::IP_PKTINFO;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(info_len) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::in_pktinfo, info);
+        }
+
+        let bytes_sent = unsafe { libc::sendmsg(fd, &msg, 0) };
+        if bytes_sent < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(bytes_sent as usize)
+        }
+    }
+
+    fn ipv4_from(addr: libc::in_addr) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from_be(addr.s_addr))
+    }
+
+    fn in_addr_from(ip: Ipv4Addr) -> libc::in_addr {
+        libc::in_addr {
+            s_addr: u32::from(ip).to_be(),
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+mod sys {
+    use priv_prelude::*;
+    use std::io;
+    use std::net::IpAddr;
+    use tokio::net::UdpSocket;
+
+    pub fn enable(_sock: &UdpSocket) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn poll_recv_from(
+        sock: &mut UdpSocket,
+        buf: &mut [u8],
+    ) -> io::Result<Async<(usize, SocketAddr, Option<IpAddr>)>> {
+        match sock.poll_recv_from(buf)? {
+            Async::Ready((bytes_read, sender_addr)) => {
+                Ok(Async::Ready((bytes_read, sender_addr, None)))
+            }
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+
+    pub fn poll_send_from(
+        sock: &mut UdpSocket,
+        buf: &[u8],
+        dest: &SocketAddr,
+        _source: Option<IpAddr>,
+    ) -> io::Result<Async<usize>> {
+        sock.poll_send_to(buf, dest)
+    }
+}
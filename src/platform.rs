@@ -0,0 +1,89 @@
+//! Operating system the node runs on, so applications can tell peers what kind of device they
+//! are, e.g. via discovery application data.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Platform set with `Platform::set_current()`: 0 means none, otherwise it's the platform index
+/// plus 1.
+static CURRENT_OVERRIDE: AtomicUsize = AtomicUsize::new(0);
+
+const PLATFORMS: [Platform; 6] = [
+    Platform::Linux,
+    Platform::Android,
+    Platform::Ios,
+    Platform::Windows,
+    Platform::MacOs,
+    Platform::Other,
+];
+
+/// Operating system family.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Platform {
+    Linux,
+    Android,
+    Ios,
+    Windows,
+    MacOs,
+    Other,
+}
+
+impl Platform {
+    /// Returns the platform set with `set_current()` or, if none was, the one detected from the
+    /// compilation target.
+    pub fn current() -> Self {
+        match CURRENT_OVERRIDE.load(Ordering::SeqCst) {
+            0 => Self::detected(),
+            i => PLATFORMS[i - 1],
+        }
+    }
+
+    /// Overrides the platform `current()` returns for the whole process, `None` reverts to
+    /// detection. Useful when the compilation target doesn't tell, e.g. for Android apps built
+    /// for a generic Linux target.
+    pub fn set_current(platform: Option<Platform>) {
+        let i = match platform {
+            Some(platform) => unwrap!(PLATFORMS.iter().position(|p| *p == platform)) + 1,
+            None => 0,
+        };
+        CURRENT_OVERRIDE.store(i, Ordering::SeqCst);
+    }
+
+    /// Detects platform by the target operating system crate was compiled for.
+    pub fn detected() -> Self {
+        if cfg!(target_os = "android") {
+            Platform::Android
+        } else if cfg!(target_os = "linux") {
+            Platform::Linux
+        } else if cfg!(target_os = "ios") {
+            Platform::Ios
+        } else if cfg!(target_os = "windows") {
+            Platform::Windows
+        } else if cfg!(target_os = "macos") {
+            Platform::MacOs
+        } else {
+            Platform::Other
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest2::prelude::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn it_detects_linux() {
+        assert_that!(Platform::detected(), eq(Platform::Linux));
+    }
+
+    #[test]
+    fn current_platform_can_be_overridden() {
+        Platform::set_current(Some(Platform::Android));
+        let overridden = Platform::current();
+        Platform::set_current(None);
+
+        assert_that!(overridden, eq(Platform::Android));
+        assert_that!(Platform::current(), eq(Platform::detected()));
+    }
+}
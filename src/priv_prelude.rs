@@ -1,13 +1,11 @@
 //! Common includes.
 
-pub use future_utils::{BoxStream, FutureExt, StreamExt};
+pub use future_utils::{BoxSendStream, FutureExt, StreamExt};
 pub use futures::{Async, Future, Stream};
 pub use peer::PeerInfo;
-pub use safe_crypto::{
-    gen_encrypt_keypair, Error as EncryptionError, PublicEncryptKey, SecretEncryptKey,
-    SharedSecretKey,
-};
-pub use serde::de::DeserializeOwned;
+#[cfg(test)]
+pub use safe_crypto::gen_encrypt_keypair;
+pub use safe_crypto::{Error as EncryptionError, PublicEncryptKey, SecretEncryptKey};
 pub use serde::Serialize;
 pub use std::net::SocketAddr;
 pub use std::time::Duration;
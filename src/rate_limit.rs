@@ -0,0 +1,104 @@
+//! Process wide cap on discovery request rate, so that an application shouting for peers from
+//! many places at once can't flood the network.
+
+use priv_prelude::*;
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Limits how many discovery requests are sent within a time window. Clones share the same
+/// limit, so give a clone of one limiter to every `DiscoveryConfig` whose traffic should be capped
+/// together. Requests over the limit are delayed, not dropped.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    /// Minimum time between requests once the burst is used up.
+    interval: Duration,
+    /// How far ahead of the schedule requests may go: this makes up the burst.
+    burst_tolerance: Duration,
+    /// Time the next request would be sent at if requests were spread evenly.
+    next_at: Arc<Mutex<Option<Instant>>>,
+}
+
+impl RateLimiter {
+    /// Allows `max_requests` requests per `period`. They may be sent in a burst, after that
+    /// requests are spread evenly. `max_requests` below 1 is treated as 1.
+    pub fn new(max_requests: u32, period: Duration) -> Self {
+        let interval = period / cmp::max(max_requests, 1);
+        Self {
+            interval,
+            burst_tolerance: period - interval,
+            next_at: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Takes a slot for one request and returns when the request may be sent.
+    pub fn reserve(&self) -> Instant {
+        self.reserve_at(Instant::now())
+    }
+
+    fn reserve_at(&self, now: Instant) -> Instant {
+        let mut next_at = unwrap!(self.next_at.lock());
+        let scheduled = cmp::max(next_at.unwrap_or(now), now);
+        let send_at = match scheduled.checked_sub(self.burst_tolerance) {
+            Some(earliest) => cmp::max(earliest, now),
+            None => now,
+        };
+        *next_at = Some(scheduled + self.interval);
+        send_at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest2::prelude::*;
+
+    #[test]
+    fn it_allows_burst_of_max_requests_and_then_spreads_them_evenly() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(1));
+        let now = Instant::now();
+
+        let slots: Vec<_> = (0..4)
+            .map(|_| limiter.reserve_at(now).duration_since(now))
+            .collect();
+
+        assert_that!(
+            slots,
+            eq(vec![
+                Duration::from_millis(0),
+                Duration::from_millis(0),
+                Duration::from_millis(500),
+                Duration::from_millis(1000),
+            ])
+        );
+    }
+
+    #[test]
+    fn idle_time_restores_the_burst() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(1));
+        let now = Instant::now();
+        let _ = limiter.reserve_at(now);
+        let _ = limiter.reserve_at(now);
+
+        let later = now + Duration::from_secs(5);
+        let slots: Vec<_> = (0..2)
+            .map(|_| limiter.reserve_at(later).duration_since(later))
+            .collect();
+
+        assert_that!(slots, eq(vec![Duration::from_millis(0); 2]));
+    }
+
+    #[test]
+    fn clones_share_the_limit() {
+        let limiter = RateLimiter::new(1, Duration::from_secs(1));
+        let other = limiter.clone();
+        let now = Instant::now();
+
+        let _ = limiter.reserve_at(now);
+
+        assert_that!(
+            other.reserve_at(now).duration_since(now),
+            eq(Duration::from_secs(1))
+        );
+    }
+}
@@ -0,0 +1,167 @@
+//! Senders of invalid discovery requests, so that a host flooding discovery server with invalid
+//! datagrams is dropped right away instead of having every datagram parsed and logged.
+//!
+//! A single invalid datagram is not enough to reject its sender: source addresses can be spoofed
+//! and hosts behind the same NAT share one. Sender is rejected only once it sends `threshold`
+//! invalid datagrams without pausing for longer than ttl.
+
+use priv_prelude::*;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// How many senders are remembered at most.
+pub const DEFAULT_CAPACITY: usize = 64;
+/// How long sender stays rejected and how long its invalid datagrams are counted for.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(30);
+
+/// Invalid datagrams received from a single sender.
+#[derive(Debug)]
+struct InvalidSender {
+    count: u32,
+    last_at: Instant,
+}
+
+/// Size bounded set of invalid datagram sender IPs with expiry. When full, the sender whose
+/// invalid datagram was seen least recently is forgotten first.
+#[derive(Debug)]
+pub struct RejectedSenders {
+    capacity: usize,
+    ttl: Duration,
+    threshold: u32,
+    senders: HashMap<IpAddr, InvalidSender>,
+    /// Senders from the least to the most recently seen.
+    order: VecDeque<IpAddr>,
+}
+
+impl RejectedSenders {
+    /// Constructs the set that rejects senders after `threshold` invalid datagrams. Values below
+    /// 1 are treated as 1.
+    pub fn new(capacity: usize, ttl: Duration, threshold: u32) -> Self {
+        Self {
+            capacity,
+            ttl,
+            threshold: cmp::max(threshold, 1),
+            senders: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Checks if sender has reached the threshold and its last invalid datagram was received
+    /// less than ttl ago.
+    pub fn contains(&self, ip: IpAddr, now: Instant) -> bool {
+        match self.senders.get(&ip) {
+            Some(sender) => sender.count >= self.threshold && !self.expired(sender, now),
+            None => false,
+        }
+    }
+
+    /// Counts invalid datagram received from a given sender at a given time. Returns `true`, if
+    /// the sender is rejected from now on.
+    pub fn record_invalid(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let count = match self.senders.get(&ip) {
+            Some(sender) if !self.expired(sender, now) => sender.count.saturating_add(1),
+            _ => 1,
+        };
+        let sender = InvalidSender {
+            count,
+            last_at: now,
+        };
+        if self.senders.insert(ip, sender).is_some() {
+            self.order.retain(|seen| *seen != ip);
+        }
+        self.order.push_back(ip);
+        while self.order.len() > self.capacity || self.front_expired(now) {
+            if let Some(oldest) = self.order.pop_front() {
+                let _ = self.senders.remove(&oldest);
+            }
+        }
+        count >= self.threshold
+    }
+
+    fn expired(&self, sender: &InvalidSender, now: Instant) -> bool {
+        now.duration_since(sender.last_at) >= self.ttl
+    }
+
+    fn front_expired(&self, now: Instant) -> bool {
+        match self.order.front().and_then(|ip| self.senders.get(ip)) {
+            Some(sender) => self.expired(sender, now),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest2::prelude::*;
+
+    #[test]
+    fn sender_is_rejected_only_after_threshold_is_reached() {
+        let mut rejected = RejectedSenders::new(4, Duration::from_secs(10), 3);
+        let now = Instant::now();
+
+        assert_that!(rejected.record_invalid(ip!("192.168.1.2"), now), is(false));
+        assert_that!(rejected.record_invalid(ip!("192.168.1.2"), now), is(false));
+        assert_that!(rejected.contains(ip!("192.168.1.2"), now), is(false));
+
+        assert_that!(rejected.record_invalid(ip!("192.168.1.2"), now), is(true));
+        assert_that!(rejected.contains(ip!("192.168.1.2"), now), is(true));
+        assert_that!(rejected.contains(ip!("192.168.1.3"), now), is(false));
+    }
+
+    #[test]
+    fn sender_stays_rejected_until_ttl_expires() {
+        let mut rejected = RejectedSenders::new(4, Duration::from_secs(10), 1);
+        let now = Instant::now();
+
+        let _ = rejected.record_invalid(ip!("192.168.1.2"), now);
+
+        assert_that!(rejected.contains(ip!("192.168.1.2"), now), is(true));
+        let later = now + Duration::from_secs(10);
+        assert_that!(rejected.contains(ip!("192.168.1.2"), later), is(false));
+    }
+
+    #[test]
+    fn invalid_datagrams_are_counted_anew_after_ttl() {
+        let mut rejected = RejectedSenders::new(4, Duration::from_secs(10), 2);
+        let now = Instant::now();
+
+        let _ = rejected.record_invalid(ip!("192.168.1.2"), now);
+        let later = now + Duration::from_secs(10);
+
+        assert_that!(
+            rejected.record_invalid(ip!("192.168.1.2"), later),
+            is(false)
+        );
+        assert_that!(rejected.contains(ip!("192.168.1.2"), later), is(false));
+    }
+
+    #[test]
+    fn when_full_least_recently_seen_sender_is_forgotten() {
+        let mut rejected = RejectedSenders::new(2, Duration::from_secs(10), 1);
+        let now = Instant::now();
+
+        let _ = rejected.record_invalid(ip!("192.168.1.2"), now);
+        let _ = rejected.record_invalid(ip!("192.168.1.3"), now);
+        let _ = rejected.record_invalid(ip!("192.168.1.4"), now);
+
+        assert_that!(rejected.contains(ip!("192.168.1.2"), now), is(false));
+        assert_that!(rejected.contains(ip!("192.168.1.3"), now), is(true));
+        assert_that!(rejected.contains(ip!("192.168.1.4"), now), is(true));
+        assert_that!(rejected.senders.len(), eq(2));
+    }
+
+    #[test]
+    fn expired_senders_are_forgotten_on_record() {
+        let mut rejected = RejectedSenders::new(4, Duration::from_secs(10), 1);
+        let now = Instant::now();
+
+        let _ = rejected.record_invalid(ip!("192.168.1.2"), now);
+        let _ = rejected.record_invalid(ip!("192.168.1.3"), now + Duration::from_secs(20));
+
+        assert_that!(rejected.senders.len(), eq(1));
+        assert_that!(rejected.order.len(), eq(1));
+    }
+}
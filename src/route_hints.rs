@@ -0,0 +1,271 @@
+//! Picks the local address to connect to a peer from, using the routing table, so that
+//! multi-homed hosts don't connect from the wrong interface. Enabled with `route-hints` feature.
+//!
+//! Only IPv4 is supported: `system_routes()` reads the IPv4 routing table and only on Linux.
+//! Connections to IPv6 peers and connections on other platforms are made from the address the OS
+//! picks. Elsewhere use `select_source_addr()` with routes of your own.
+
+use futures::future;
+use get_if_addrs::{get_if_addrs, Interface};
+use priv_prelude::*;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::cmp::Reverse;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use subnet::Subnet;
+use tokio::net::TcpStream;
+use tokio::reactor::Handle;
+
+/// `RTF_UP`: route is usable.
+const RTF_UP: u16 = 0x1;
+
+/// Routing table entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    /// Destination network.
+    pub dest: Subnet,
+    /// Name of the interface packets to the destination leave through.
+    pub iface: String,
+    /// Among routes with equally long prefixes, the one with lower metric is preferred.
+    pub metric: u32,
+}
+
+/// Picks the local address to connect to a given peer IP from: an address of the interface the
+/// most specific route to the peer goes through. Returns `None`, if there's no such route, the
+/// interface has no address of the peer's IP family or the peer is on our host.
+pub fn select_source_addr(
+    peer_ip: IpAddr,
+    routes: &[Route],
+    ifaces: &[Interface],
+) -> Option<IpAddr> {
+    if peer_ip.is_loopback() {
+        return None;
+    }
+    let route = routes
+        .iter()
+        .filter(|route| route.dest.contains(peer_ip))
+        .min_by_key(|route| (Reverse(route.dest.prefix_len()), route.metric))?;
+    ifaces
+        .iter()
+        .filter(|iface| iface.name == route.iface)
+        .map(|iface| iface.ip())
+        .find(|ip| ip.is_ipv4() == peer_ip.is_ipv4())
+}
+
+/// Reads the IPv4 routing table of the system. Fails on platforms other than Linux.
+pub fn system_routes() -> io::Result<Vec<Route>> {
+    if cfg!(target_os = "linux") {
+        let table = fs::read_to_string("/proc/net/route")?;
+        Ok(parse_proc_net_route(&table))
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Reading routing table is not supported on this platform",
+        ))
+    }
+}
+
+/// Connects to a given peer from the local address picked by `select_source_addr()` with the
+/// system routing table and interfaces. If there's no address to pick or the routing table can't
+/// be read, OS picks one as usual. The connection is registered with a given reactor.
+pub fn connect_via_best_route(
+    peer_addr: &SocketAddr,
+    handle: &Handle,
+) -> impl Future<Item = TcpStream, Error = io::Error> {
+    let (peer_addr, handle) = (*peer_addr, handle.clone());
+    future::result(bind_tcp_for(&peer_addr))
+        .and_then(move |sock| TcpStream::connect_std(sock, &peer_addr, &handle))
+}
+
+/// Constructs TCP socket bound to the best source address to connect to a given peer from.
+/// The socket is left unbound, when routes or interfaces can't be listed.
+fn bind_tcp_for(peer_addr: &SocketAddr) -> io::Result<::std::net::TcpStream> {
+    let source_ip = match (system_routes(), get_if_addrs()) {
+        (Ok(routes), Ok(ifaces)) => select_source_addr(peer_addr.ip(), &routes, &ifaces),
+        (Err(e), _) | (_, Err(e)) => {
+            debug!("Not picking source address for {}: {}", peer_addr, e);
+            None
+        }
+    };
+    let domain = match *peer_addr {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    };
+    let sock = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    if let Some(ip) = source_ip {
+        sock.bind(&SockAddr::from(SocketAddr::new(ip, 0)))?;
+    }
+    Ok(sock.into_tcp_stream())
+}
+
+/// Parses `/proc/net/route`. Lines that can't be parsed and routes that are not up are skipped.
+fn parse_proc_net_route(table: &str) -> Vec<Route> {
+    table.lines().skip(1).filter_map(parse_route_line).collect()
+}
+
+fn parse_route_line(line: &str) -> Option<Route> {
+    let fields: Vec<_> = line.split_whitespace().collect();
+    if fields.len() < 8 {
+        return None;
+    }
+    let flags = u16::from_str_radix(fields[3], 16).ok()?;
+    if flags & RTF_UP == 0 {
+        return None;
+    }
+    let dest = parse_hex_ipv4(fields[1])?;
+    let mask = u32::from(parse_hex_ipv4(fields[7])?);
+    Some(Route {
+        dest: Subnet::new(IpAddr::V4(dest), mask.count_ones() as u8).ok()?,
+        iface: fields[0].to_owned(),
+        metric: fields[6].parse().ok()?,
+    })
+}
+
+/// Kernel prints addresses as hex of their network byte order bytes read as a native integer.
+fn parse_hex_ipv4(hex: &str) -> Option<Ipv4Addr> {
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    Some(Ipv4Addr::from(value.to_ne_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use get_if_addrs::{IfAddr, Ifv4Addr};
+    use hamcrest2::prelude::*;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn route(dest: &str, iface: &str, metric: u32) -> Route {
+        Route {
+            dest: unwrap!(dest.parse()),
+            iface: iface.to_owned(),
+            metric,
+        }
+    }
+
+    fn iface(name: &str, ip: Ipv4Addr) -> Interface {
+        Interface {
+            name: name.to_owned(),
+            addr: IfAddr::V4(Ifv4Addr {
+                ip,
+                netmask: ipv4!("255.255.255.0"),
+                broadcast: None,
+            }),
+        }
+    }
+
+    fn ifaces() -> Vec<Interface> {
+        vec![
+            iface("eth0", ipv4!("192.168.1.5")),
+            iface("wlan0", ipv4!("10.0.0.5")),
+            iface("tun0", ipv4!("172.16.0.5")),
+        ]
+    }
+
+    mod select_source_addr {
+        use super::*;
+
+        #[test]
+        fn it_picks_address_of_interface_with_longest_prefix_route() {
+            let routes = vec![
+                route("0.0.0.0/0", "eth0", 0),
+                route("10.0.0.0/8", "tun0", 0),
+                route("10.0.0.0/24", "wlan0", 600),
+            ];
+
+            let ip = select_source_addr(ip!("10.0.0.7"), &routes, &ifaces());
+
+            assert_that!(ip, eq(Some(ip!("10.0.0.5"))));
+        }
+
+        #[test]
+        fn it_falls_back_to_default_route() {
+            let routes = vec![
+                route("0.0.0.0/0", "eth0", 0),
+                route("10.0.0.0/24", "wlan0", 0),
+            ];
+
+            let ip = select_source_addr(ip!("8.8.8.8"), &routes, &ifaces());
+
+            assert_that!(ip, eq(Some(ip!("192.168.1.5"))));
+        }
+
+        #[test]
+        fn among_equally_specific_routes_it_prefers_lower_metric() {
+            let routes = vec![
+                route("0.0.0.0/0", "wlan0", 600),
+                route("0.0.0.0/0", "eth0", 100),
+            ];
+
+            let ip = select_source_addr(ip!("8.8.8.8"), &routes, &ifaces());
+
+            assert_that!(ip, eq(Some(ip!("192.168.1.5"))));
+        }
+
+        #[test]
+        fn when_there_is_no_route_it_returns_none() {
+            let routes = vec![route("10.0.0.0/24", "wlan0", 0)];
+
+            let ip = select_source_addr(ip!("8.8.8.8"), &routes, &ifaces());
+
+            assert_that!(ip, none());
+        }
+
+        #[test]
+        fn when_interface_has_no_address_of_peer_family_it_returns_none() {
+            let routes = vec![route("::/0", "eth0", 0)];
+
+            let ip = select_source_addr(ip!("fd00::1"), &routes, &ifaces());
+
+            assert_that!(ip, none());
+        }
+
+        #[test]
+        fn when_peer_is_on_our_host_it_returns_none() {
+            let routes = vec![route("0.0.0.0/0", "eth0", 0)];
+
+            let ip = select_source_addr(ip!("127.0.0.1"), &routes, &ifaces());
+
+            assert_that!(ip, none());
+        }
+    }
+
+    #[test]
+    fn connect_via_best_route_connects_to_peer() {
+        let mut evloop = unwrap!(Runtime::new());
+        let listener = unwrap!(::std::net::TcpListener::bind("127.0.0.1:0"));
+        let peer_addr = unwrap!(listener.local_addr());
+
+        let stream =
+            unwrap!(evloop.block_on(connect_via_best_route(&peer_addr, &Handle::default())));
+
+        assert_that!(unwrap!(stream.peer_addr()), eq(peer_addr));
+    }
+
+    mod parse_proc_net_route {
+        use super::*;
+
+        #[test]
+        fn it_parses_routes_that_are_up() {
+            let dest = u32::from_ne_bytes([192, 168, 1, 0]);
+            let mask = u32::from_ne_bytes([255, 255, 255, 0]);
+            let table = format!(
+                "Iface\tDestination\tGateway\tFlags\tRefCnt\tUse\tMetric\tMask\tMTU\tWindow\tIRTT\n\
+                 eth0\t{:08X}\t00000000\t0001\t0\t0\t100\t{:08X}\t0\t0\t0\n\
+                 eth1\t{:08X}\t00000000\t0000\t0\t0\t100\t{:08X}\t0\t0\t0\n\
+                 wlan0\t00000000\t0100000A\t0003\t0\t0\t600\t00000000\t0\t0\t0\n",
+                dest, mask, dest, mask
+            );
+
+            let routes = parse_proc_net_route(&table);
+
+            assert_that!(
+                routes,
+                eq(vec![
+                    route("192.168.1.0/24", "eth0", 100),
+                    route("0.0.0.0/0", "wlan0", 600),
+                ])
+            );
+        }
+    }
+}
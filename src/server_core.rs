@@ -0,0 +1,675 @@
+//! Peer discovery server protocol logic that does no IO on its own. It's fed with received
+//! datagrams and tells what datagrams should be sent in return, so it can be driven by any socket
+//! implementation: tokio, other async runtimes, blocking sockets, etc.
+
+use peer_discovery::{
+    DiscoveryConfig, DiscoveryError, DiscoveryMsg, DiscoveryResponse, MAX_APP_DATA_LEN,
+};
+use priv_prelude::*;
+use rejected_senders::{self, RejectedSenders};
+use std::cmp;
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// At most this many bytes of invalid datagrams are logged.
+const MAX_LOGGED_BYTES: usize = 32;
+
+/// Datagram that has to be sent.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Transmit {
+    /// Where to send the datagram.
+    pub dest: SocketAddr,
+    /// Datagram payload.
+    pub data: Vec<u8>,
+    /// Local address to send the datagram from: the one request arrived on, if it's known.
+    /// `None` lets OS pick the source address.
+    pub source: Option<IpAddr>,
+}
+
+/// Peer discovery server state machine. `DiscoveryServer` drives it with a tokio UDP socket.
+pub struct ServerCore {
+    /// Addresses peer discovery will respond with.
+    our_addrs: Vec<SocketAddr>,
+    our_pk: PublicEncryptKey,
+    /// Application data attached to every response.
+    app_data: Vec<u8>,
+    /// Application data given in config. `app_data` is empty instead, when it doesn't fit into
+    /// the response with our addresses.
+    configured_app_data: Vec<u8>,
+    /// Clients still waiting for response and local addresses their requests arrived on.
+    clients: Vec<(SocketAddr, Option<IpAddr>, PublicEncryptKey)>,
+    max_queued_clients: usize,
+    /// Requests dropped because client queue was full.
+    dropped_requests: u64,
+    respond_to_self: bool,
+    /// Senders of invalid requests, their datagrams are dropped without looking at them.
+    rejected: RejectedSenders,
+}
+
+impl ServerCore {
+    /// Constructs server state machine that will respond with given addresses. Link-local IPv6
+    /// addresses are skipped, see `DiscoveryServer::new()`. Fails, if there are no addresses
+    /// left to respond with or `config.app_data` is longer than `MAX_APP_DATA_LEN`.
+    pub fn new(
+        our_addrs: Vec<SocketAddr>,
+        our_pk: &PublicEncryptKey,
+        config: &DiscoveryConfig,
+    ) -> Result<Self, DiscoveryError> {
+        let our_addrs = advertisable_addrs(our_addrs);
+        if our_addrs.is_empty() {
+            return Err(DiscoveryError::NoAddrs);
+        }
+        if config.app_data.len() > MAX_APP_DATA_LEN {
+            return Err(DiscoveryError::AppDataTooLong(config.app_data.len()));
+        }
+        let resp = DiscoveryResponse {
+            pub_key: *our_pk,
+            addrs: our_addrs,
+            app_data: config.app_data.clone(),
+        }.fit_into_datagram();
+        Ok(Self {
+            our_addrs: resp.addrs,
+            our_pk: *our_pk,
+            app_data: resp.app_data,
+            configured_app_data: config.app_data.clone(),
+            clients: Vec::new(),
+            max_queued_clients: config.max_queued_clients,
+            dropped_requests: 0,
+            respond_to_self: config.include_self,
+            rejected: RejectedSenders::new(
+                rejected_senders::DEFAULT_CAPACITY,
+                rejected_senders::DEFAULT_TTL,
+                config.max_invalid_requests,
+            ),
+        })
+    }
+
+    /// By default server ignores requests signed with its own public key. This allows to change
+    /// that.
+    pub fn set_respond_to_self(&mut self, respond: bool) {
+        self.respond_to_self = respond;
+    }
+
+    /// Changes addresses server responds with. Addresses are filtered the same way `new()` does
+    /// it, if none are left, server ignores requests until it's given some.
+    pub fn set_our_addrs(&mut self, our_addrs: Vec<SocketAddr>) {
+        let resp = DiscoveryResponse {
+            pub_key: self.our_pk,
+            addrs: advertisable_addrs(our_addrs),
+            app_data: self.configured_app_data.clone(),
+        }.fit_into_datagram();
+        if resp.addrs.is_empty() {
+            warn!("No addresses to respond with, ignoring discovery requests");
+        }
+        self.our_addrs = resp.addrs;
+        self.app_data = resp.app_data;
+    }
+
+    /// Returns how many requests were dropped so far because too many clients were already
+    /// waiting for response.
+    pub fn dropped_requests(&self) -> u64 {
+        self.dropped_requests
+    }
+
+    /// Handles datagram received from a given address. Once a host sends
+    /// `DiscoveryConfig::max_invalid_requests` invalid requests, its datagrams are ignored for a
+    /// while. Valid discovery messages that are not requests and requests of other protocol
+    /// versions are ignored without counting them as invalid.
+    pub fn handle_datagram(&mut self, sender_addr: SocketAddr, buf: &[u8]) {
+        self.handle_datagram_on(sender_addr, None, buf)
+    }
+
+    /// Same as `handle_datagram()` but also takes the local address datagram was sent to, so
+    /// that the response would be sent from it, see `Transmit::source`.
+    pub fn handle_datagram_on(
+        &mut self,
+        sender_addr: SocketAddr,
+        local_ip: Option<IpAddr>,
+        buf: &[u8],
+    ) {
+        self.handle_datagram_at(sender_addr, local_ip, buf, Instant::now())
+    }
+
+    fn handle_datagram_at(
+        &mut self,
+        sender_addr: SocketAddr,
+        local_ip: Option<IpAddr>,
+        buf: &[u8],
+        now: Instant,
+    ) {
+        if self.rejected.contains(sender_addr.ip(), now) {
+            return;
+        }
+        match DiscoveryMsg::deserialize(buf) {
+            Ok(DiscoveryMsg::Request(their_pk)) => {
+                self.accept_request(sender_addr, local_ip, their_pk)
+            }
+            // beacons share the discovery port, they're handled by `listen_for_beacons()`
+            Ok(DiscoveryMsg::Beacon(_)) => (),
+            Ok(DiscoveryMsg::Response(_)) => {
+                debug!("Ignoring discovery response from {}", sender_addr)
+            }
+            Err(DiscoveryError::UnsupportedVersion(version)) => {
+                debug!(
+                    "Ignoring discovery request of protocol version {} from {}",
+                    version, sender_addr
+                );
+            }
+            Err(_) => {
+                warn!(
+                    "Invalid peer discovery request from {} ({} bytes): {:?}",
+                    sender_addr,
+                    buf.len(),
+                    &buf[..cmp::min(buf.len(), MAX_LOGGED_BYTES)]
+                );
+                if self.rejected.record_invalid(sender_addr.ip(), now) {
+                    warn!(
+                        "Too many invalid requests from {}, ignoring it for a while",
+                        sender_addr.ip()
+                    );
+                }
+            }
+        }
+    }
+
+    /// Handles already deserialized discovery request received from a given address.
+    pub fn handle_request(&mut self, sender_addr: SocketAddr, their_pk: PublicEncryptKey) {
+        self.accept_request(sender_addr, None, their_pk)
+    }
+
+    fn accept_request(
+        &mut self,
+        sender_addr: SocketAddr,
+        local_ip: Option<IpAddr>,
+        their_pk: PublicEncryptKey,
+    ) {
+        if self.our_addrs.is_empty() {
+            return;
+        }
+        if their_pk != self.our_pk || self.respond_to_self {
+            self.queue_client(sender_addr, local_ip, their_pk);
+        }
+    }
+
+    fn queue_client(
+        &mut self,
+        addr: SocketAddr,
+        local_ip: Option<IpAddr>,
+        their_pk: PublicEncryptKey,
+    ) {
+        if self.clients.len() < self.max_queued_clients {
+            self.clients.push((addr, local_ip, their_pk));
+            return;
+        }
+        if self.dropped_requests == 0 {
+            warn!(
+                "Discovery server queue is full ({} clients), dropping requests",
+                self.max_queued_clients
+            );
+        }
+        self.dropped_requests += 1;
+    }
+
+    /// Returns next datagram to send, if any. Datagrams should be sent in the order they are
+    /// returned. If socket can't take the datagram right away, it's up to the caller to hold on
+    /// to it until it can.
+    pub fn poll_transmit(&mut self) -> Option<Transmit> {
+        while let Some((dest, source, their_pk)) = self.clients.pop() {
+            if let Some(data) = self.make_response(&their_pk) {
+                return Some(Transmit { dest, data, source });
+            }
+        }
+        None
+    }
+
+    /// Encrypt response with their public key.
+    fn make_response(&self, their_pk: &PublicEncryptKey) -> Option<Vec<u8>> {
+        let resp = DiscoveryResponse {
+            pub_key: self.our_pk,
+            addrs: self.our_addrs.clone(),
+            app_data: self.app_data.clone(),
+        };
+        DiscoveryMsg::encrypted_response(their_pk, resp).ok()
+    }
+}
+
+/// Drops addresses that are useless to peers.
+fn advertisable_addrs(our_addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    our_addrs
+        .into_iter()
+        .filter(|addr| {
+            let link_local = is_link_local_v6(addr);
+            if link_local {
+                debug!("Not advertising link-local address {}", addr);
+            }
+            !link_local
+        }).collect()
+}
+
+/// Checks if address is IPv6 link-local, fe80::/10.
+fn is_link_local_v6(addr: &SocketAddr) -> bool {
+    match *addr {
+        SocketAddr::V6(ref addr) => addr.ip().segments()[0] & 0xffc0 == 0xfe80,
+        SocketAddr::V4(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use beacon::BeaconMsg;
+    use hamcrest2::prelude::*;
+    use peer_discovery::DEFAULT_MAX_INVALID_REQUESTS;
+    use safe_crypto::gen_sign_keypair;
+    use std::net::SocketAddrV4;
+
+    fn decrypt_response(
+        data: &[u8],
+        pk: &PublicEncryptKey,
+        sk: &SecretEncryptKey,
+    ) -> DiscoveryResponse {
+        let plaintext = unwrap!(sk.anonymously_decrypt_bytes(data, pk));
+        match unwrap!(DiscoveryMsg::deserialize(&plaintext)) {
+            DiscoveryMsg::Response(resp) => resp,
+            _ => panic!("Expected discovery response"),
+        }
+    }
+
+    mod new {
+        use super::*;
+
+        #[test]
+        fn it_trims_response_to_safe_datagram_size() {
+            let (server_pk, _sk) = gen_encrypt_keypair();
+            let our_addrs: Vec<_> = (0..200)
+                .map(|i| SocketAddr::V4(SocketAddrV4::new(ipv4!("192.168.1.100"), 1000 + i)))
+                .collect();
+            let config = DiscoveryConfig {
+                app_data: vec![1; MAX_APP_DATA_LEN],
+                ..Default::default()
+            };
+            let core = unwrap!(ServerCore::new(our_addrs.clone(), &server_pk, &config));
+
+            let (their_pk, their_sk) = gen_encrypt_keypair();
+            let data = unwrap!(core.make_response(&their_pk));
+            assert_that!(data.len(), less_than_or_equal_to(::SAFE_RESPONSE_SIZE));
+
+            let resp = decrypt_response(&data, &their_pk, &their_sk);
+            assert_that!(&resp.app_data, empty());
+            assert_that!(resp.addrs.len(), less_than(our_addrs.len()));
+            assert_that!(&resp.addrs[..], eq(&our_addrs[..resp.addrs.len()]));
+        }
+
+        #[test]
+        fn it_drops_app_data_first_when_response_is_too_big() {
+            let (server_pk, _sk) = gen_encrypt_keypair();
+            let our_addrs: Vec<_> = (0..150)
+                .map(|i| SocketAddr::V4(SocketAddrV4::new(ipv4!("192.168.1.100"), 1000 + i)))
+                .collect();
+            let config = DiscoveryConfig {
+                app_data: vec![1; MAX_APP_DATA_LEN],
+                ..Default::default()
+            };
+            let core = unwrap!(ServerCore::new(our_addrs.clone(), &server_pk, &config));
+
+            assert_that!(&core.app_data, empty());
+            assert_that!(&core.our_addrs, eq(&our_addrs));
+        }
+
+        #[test]
+        fn it_fails_when_app_data_is_too_long() {
+            let (server_pk, _sk) = gen_encrypt_keypair();
+            let config = DiscoveryConfig {
+                app_data: vec![1; MAX_APP_DATA_LEN + 1],
+                ..Default::default()
+            };
+
+            let res = ServerCore::new(vec![addr!("192.168.1.100:1234")], &server_pk, &config);
+
+            match res {
+                Err(DiscoveryError::AppDataTooLong(len)) => {
+                    assert_that!(len, eq(MAX_APP_DATA_LEN + 1))
+                }
+                _ => panic!("Expected AppDataTooLong error"),
+            }
+        }
+
+        #[test]
+        fn it_fails_when_there_are_no_addresses_to_respond_with() {
+            let (server_pk, _sk) = gen_encrypt_keypair();
+
+            let res = ServerCore::new(Vec::new(), &server_pk, &Default::default());
+
+            match res {
+                Err(DiscoveryError::NoAddrs) => (),
+                _ => panic!("Expected NoAddrs error"),
+            }
+        }
+
+        #[test]
+        fn it_does_not_advertise_link_local_ipv6_addresses() {
+            let (server_pk, _sk) = gen_encrypt_keypair();
+            let our_addrs = vec![
+                addr!("[fe80::1]:1234"),
+                addr!("192.168.1.100:1234"),
+                addr!("[febf::1]:1234"),
+                addr!("[fd00::1]:1234"),
+                addr!("169.254.1.1:1234"),
+            ];
+
+            let core = unwrap!(ServerCore::new(our_addrs, &server_pk, &Default::default()));
+
+            let expected_addrs = vec![
+                addr!("192.168.1.100:1234"),
+                addr!("[fd00::1]:1234"),
+                addr!("169.254.1.1:1234"),
+            ];
+            assert_that!(&core.our_addrs, eq(&expected_addrs));
+        }
+
+        #[test]
+        fn it_fails_when_there_are_only_link_local_ipv6_addresses() {
+            let (server_pk, _sk) = gen_encrypt_keypair();
+
+            let res = ServerCore::new(
+                vec![addr!("[fe80::1]:1234")],
+                &server_pk,
+                &Default::default(),
+            );
+
+            match res {
+                Err(DiscoveryError::NoAddrs) => (),
+                _ => panic!("Expected NoAddrs error"),
+            }
+        }
+    }
+
+    mod set_our_addrs {
+        use super::*;
+
+        #[test]
+        fn it_ignores_requests_while_there_are_no_addresses_to_respond_with() {
+            let (server_pk, _sk) = gen_encrypt_keypair();
+            let mut core = unwrap!(ServerCore::new(
+                vec![addr!("192.168.1.100:1234")],
+                &server_pk,
+                &Default::default()
+            ));
+            let (their_pk, _their_sk) = gen_encrypt_keypair();
+            let req = unwrap!(DiscoveryMsg::serialized_request(their_pk));
+
+            core.set_our_addrs(vec![addr!("[fe80::1]:1234")]);
+            core.handle_datagram(addr!("192.168.1.2:5000"), &req);
+
+            assert_that!(core.poll_transmit(), none());
+        }
+
+        #[test]
+        fn it_advertises_configured_app_data_again_once_it_fits() {
+            let (server_pk, _sk) = gen_encrypt_keypair();
+            let config = DiscoveryConfig {
+                app_data: vec![1; MAX_APP_DATA_LEN],
+                ..Default::default()
+            };
+            let few_addrs = vec![addr!("192.168.1.100:1234")];
+            let mut core = unwrap!(ServerCore::new(few_addrs.clone(), &server_pk, &config));
+            let many_addrs: Vec<_> = (0..150)
+                .map(|i| SocketAddr::V4(SocketAddrV4::new(ipv4!("192.168.1.100"), 1000 + i)))
+                .collect();
+
+            core.set_our_addrs(many_addrs);
+            assert_that!(&core.app_data, empty());
+            core.set_our_addrs(few_addrs);
+
+            let (their_pk, their_sk) = gen_encrypt_keypair();
+            let data = unwrap!(core.make_response(&their_pk));
+            let resp = decrypt_response(&data, &their_pk, &their_sk);
+            assert_that!(resp.app_data, eq(vec![1; MAX_APP_DATA_LEN]));
+        }
+    }
+
+    mod handle_datagram {
+        use super::*;
+
+        #[test]
+        fn it_queues_response_to_request_sender() {
+            let (server_pk, _sk) = gen_encrypt_keypair();
+            let our_addrs = vec![addr!("192.168.1.100:1234")];
+            let mut core = unwrap!(ServerCore::new(
+                our_addrs.clone(),
+                &server_pk,
+                &Default::default()
+            ));
+            let (their_pk, their_sk) = gen_encrypt_keypair();
+
+            let req = unwrap!(DiscoveryMsg::serialized_request(their_pk));
+            core.handle_datagram(addr!("192.168.1.2:5000"), &req);
+
+            let transmit = unwrap!(core.poll_transmit());
+            assert_that!(transmit.dest, eq(addr!("192.168.1.2:5000")));
+            let resp = decrypt_response(&transmit.data, &their_pk, &their_sk);
+            assert_that!(resp.pub_key, eq(server_pk));
+            assert_that!(resp.addrs, eq(our_addrs));
+            assert_that!(transmit.source, none());
+            assert_that!(core.poll_transmit(), none());
+        }
+
+        #[test]
+        fn response_is_sent_from_the_address_request_arrived_on() {
+            let (server_pk, _sk) = gen_encrypt_keypair();
+            let mut core = unwrap!(ServerCore::new(
+                vec![addr!("192.168.1.100:1234")],
+                &server_pk,
+                &Default::default()
+            ));
+            let (their_pk, _their_sk) = gen_encrypt_keypair();
+
+            let req = unwrap!(DiscoveryMsg::serialized_request(their_pk));
+            core.handle_datagram_on(addr!("192.168.1.2:5000"), Some(ip!("192.168.1.101")), &req);
+
+            let transmit = unwrap!(core.poll_transmit());
+            assert_that!(transmit.source, eq(Some(ip!("192.168.1.101"))));
+        }
+
+        #[test]
+        fn it_ignores_datagrams_from_sender_of_invalid_request() {
+            let (server_pk, _sk) = gen_encrypt_keypair();
+            let mut core = unwrap!(ServerCore::new(
+                vec![addr!("192.168.1.100:1234")],
+                &server_pk,
+                &Default::default()
+            ));
+            let (their_pk, _their_sk) = gen_encrypt_keypair();
+            let req = unwrap!(DiscoveryMsg::serialized_request(their_pk));
+
+            for _ in 0..DEFAULT_MAX_INVALID_REQUESTS {
+                core.handle_datagram(addr!("192.168.1.2:5000"), &[0xff; 8]);
+            }
+            core.handle_datagram(addr!("192.168.1.2:5001"), &req);
+            assert_that!(core.poll_transmit(), none());
+
+            core.handle_datagram(addr!("192.168.1.3:5000"), &req);
+            assert_that!(core.poll_transmit().is_some(), is(true));
+        }
+
+        #[test]
+        fn it_handles_requests_from_sender_of_fewer_invalid_requests_than_allowed() {
+            let (server_pk, _sk) = gen_encrypt_keypair();
+            let config = DiscoveryConfig {
+                max_invalid_requests: 3,
+                ..Default::default()
+            };
+            let mut core = unwrap!(ServerCore::new(
+                vec![addr!("192.168.1.100:1234")],
+                &server_pk,
+                &config
+            ));
+            let (their_pk, _their_sk) = gen_encrypt_keypair();
+            let req = unwrap!(DiscoveryMsg::serialized_request(their_pk));
+
+            core.handle_datagram(addr!("192.168.1.2:5000"), &[0xff; 8]);
+            core.handle_datagram(addr!("192.168.1.2:5000"), &[0xff; 8]);
+            core.handle_datagram(addr!("192.168.1.2:5001"), &req);
+
+            assert_that!(core.poll_transmit().is_some(), is(true));
+        }
+
+        #[test]
+        fn it_does_not_count_responses_as_invalid_requests() {
+            let (server_pk, _sk) = gen_encrypt_keypair();
+            let config = DiscoveryConfig {
+                max_invalid_requests: 1,
+                ..Default::default()
+            };
+            let mut core = unwrap!(ServerCore::new(
+                vec![addr!("192.168.1.100:1234")],
+                &server_pk,
+                &config
+            ));
+            let (their_pk, _their_sk) = gen_encrypt_keypair();
+            let resp = DiscoveryMsg::Response(DiscoveryResponse {
+                pub_key: their_pk,
+                addrs: vec![addr!("192.168.1.2:1234")],
+                app_data: Vec::new(),
+            });
+            let resp = unwrap!(resp.serialize());
+            let req = unwrap!(DiscoveryMsg::serialized_request(their_pk));
+
+            core.handle_datagram(addr!("192.168.1.2:5000"), &resp);
+            core.handle_datagram(addr!("192.168.1.2:5000"), &req);
+
+            assert_that!(core.poll_transmit().is_some(), is(true));
+        }
+
+        #[test]
+        fn it_ignores_beacons_without_rejecting_their_sender() {
+            let (server_pk, _sk) = gen_encrypt_keypair();
+            let config = DiscoveryConfig {
+                max_invalid_requests: 1,
+                ..Default::default()
+            };
+            let mut core = unwrap!(ServerCore::new(
+                vec![addr!("192.168.1.100:1234")],
+                &server_pk,
+                &config
+            ));
+            let (their_pk, _their_sk) = gen_encrypt_keypair();
+            let (sign_pk, sign_sk) = gen_sign_keypair();
+            let beacon = DiscoveryMsg::Beacon(BeaconMsg::new(&their_pk, 1234, &sign_pk, &sign_sk));
+            let beacon = unwrap!(beacon.serialize());
+            let req = unwrap!(DiscoveryMsg::serialized_request(their_pk));
+
+            core.handle_datagram(addr!("192.168.1.2:5000"), &beacon);
+            assert_that!(core.poll_transmit(), none());
+
+            core.handle_datagram(addr!("192.168.1.2:5000"), &req);
+            assert_that!(core.poll_transmit().is_some(), is(true));
+        }
+
+        #[test]
+        fn it_does_not_reject_senders_of_other_protocol_versions() {
+            let (server_pk, _sk) = gen_encrypt_keypair();
+            let config = DiscoveryConfig {
+                max_invalid_requests: 1,
+                ..Default::default()
+            };
+            let mut core = unwrap!(ServerCore::new(
+                vec![addr!("192.168.1.100:1234")],
+                &server_pk,
+                &config
+            ));
+            let (their_pk, _their_sk) = gen_encrypt_keypair();
+            let mut legacy_req = vec![0, 0, 0, 0];
+            legacy_req.extend_from_slice(&their_pk.into_bytes());
+            let req = unwrap!(DiscoveryMsg::serialized_request(their_pk));
+
+            core.handle_datagram(addr!("192.168.1.2:5000"), &legacy_req);
+            assert_that!(core.poll_transmit(), none());
+
+            core.handle_datagram(addr!("192.168.1.2:5000"), &req);
+            assert_that!(core.poll_transmit().is_some(), is(true));
+        }
+
+        #[test]
+        fn it_handles_requests_from_rejected_sender_again_after_a_while() {
+            let (server_pk, _sk) = gen_encrypt_keypair();
+            let mut core = unwrap!(ServerCore::new(
+                vec![addr!("192.168.1.100:1234")],
+                &server_pk,
+                &Default::default()
+            ));
+            let (their_pk, _their_sk) = gen_encrypt_keypair();
+            let req = unwrap!(DiscoveryMsg::serialized_request(their_pk));
+            let now = Instant::now();
+
+            for _ in 0..DEFAULT_MAX_INVALID_REQUESTS {
+                core.handle_datagram_at(addr!("192.168.1.2:5000"), None, &[0xff; 8], now);
+            }
+            let later = now + rejected_senders::DEFAULT_TTL;
+            core.handle_datagram_at(addr!("192.168.1.2:5000"), None, &req, later);
+
+            assert_that!(core.poll_transmit().is_some(), is(true));
+        }
+
+        #[test]
+        fn it_drops_requests_when_client_queue_is_full() {
+            let (server_pk, _sk) = gen_encrypt_keypair();
+            let config = DiscoveryConfig {
+                max_queued_clients: 10,
+                ..Default::default()
+            };
+            let mut core = unwrap!(ServerCore::new(
+                vec![addr!("192.168.1.100:1234")],
+                &server_pk,
+                &config
+            ));
+            let (their_pk, _their_sk) = gen_encrypt_keypair();
+            let req = unwrap!(DiscoveryMsg::serialized_request(their_pk));
+
+            for port in 0..1000 {
+                let sender_addr = SocketAddr::V4(SocketAddrV4::new(ipv4!("192.168.1.2"), port));
+                core.handle_datagram(sender_addr, &req);
+                assert_that!(core.clients.len(), less_than_or_equal_to(10));
+            }
+
+            assert_that!(core.dropped_requests(), eq(990));
+            let mut responses = 0;
+            while core.poll_transmit().is_some() {
+                responses += 1;
+            }
+            assert_that!(responses, eq(10));
+        }
+
+        #[test]
+        fn it_ignores_invalid_requests() {
+            let (server_pk, _sk) = gen_encrypt_keypair();
+            let mut core = unwrap!(ServerCore::new(
+                vec![addr!("192.168.1.100:1234")],
+                &server_pk,
+                &Default::default()
+            ));
+
+            core.handle_datagram(addr!("192.168.1.2:5000"), &[1, 2, 3]);
+
+            assert_that!(core.poll_transmit(), none());
+        }
+
+        #[test]
+        fn it_ignores_requests_from_self_unless_configured_otherwise() {
+            let (server_pk, _sk) = gen_encrypt_keypair();
+            let mut core = unwrap!(ServerCore::new(
+                vec![addr!("192.168.1.100:1234")],
+                &server_pk,
+                &Default::default()
+            ));
+            let req = unwrap!(DiscoveryMsg::serialized_request(server_pk));
+
+            core.handle_datagram(addr!("192.168.1.2:5000"), &req);
+            assert_that!(core.poll_transmit(), none());
+
+            core.set_respond_to_self(true);
+            core.handle_datagram(addr!("192.168.1.2:5000"), &req);
+            assert_that!(core.poll_transmit(), some());
+        }
+    }
+}
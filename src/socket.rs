@@ -0,0 +1,129 @@
+//! UDP socket construction with custom socket options.
+
+use priv_prelude::*;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::io;
+use std::net;
+use tokio::net::UdpSocket;
+use tokio::reactor::Handle;
+
+/// Options applied to discovery sockets before they are bound. `None` leaves OS defaults.
+///
+/// Note that most operating systems treat buffer sizes as hints: Linux, for example, doubles the
+/// requested value and caps it with `net.core.rmem_max`/`net.core.wmem_max`.
+#[derive(Debug, Clone, Default)]
+pub struct SocketOptions {
+    /// `SO_RCVBUF`. Bigger receive buffer helps not to drop responses during bursts.
+    pub recv_buffer_size: Option<usize>,
+    /// `SO_SNDBUF`.
+    pub send_buffer_size: Option<usize>,
+    /// `IP_TTL` of outgoing unicast and broadcast packets.
+    pub ttl: Option<u32>,
+    /// `IP_MULTICAST_LOOP`. Only relevant for multicast traffic.
+    pub multicast_loop: Option<bool>,
+    /// `IP_MULTICAST_TTL`. OS default is 1 which keeps multicast packets within the local network,
+    /// bigger values let them cross routers that forward multicast.
+    pub multicast_ttl: Option<u32>,
+    /// `SO_REUSEADDR`. Lets multiple sockets on the same host bind the same port, each of them
+    /// then receives broadcast requests.
+    pub reuse_addr: Option<bool>,
+    /// Reactor sockets are registered with. `None` means the default reactor: the one of the
+    /// runtime sockets are created on or, outside of any runtime, tokio's background reactor.
+    /// Set this when driving discovery on a reactor of your own.
+    pub reactor: Option<Handle>,
+}
+
+/// Creates UDP socket with given options, binds it to a given address and registers with the
+/// reactor from the options.
+pub fn bind_udp(addr: &SocketAddr, opts: &SocketOptions) -> io::Result<UdpSocket> {
+    let sock = bind_std_udp(addr, opts)?;
+    match opts.reactor {
+        Some(ref handle) => UdpSocket::from_std(sock, handle),
+        None => UdpSocket::from_std(sock, &Handle::default()),
+    }
+}
+
+fn bind_std_udp(addr: &SocketAddr, opts: &SocketOptions) -> io::Result<net::UdpSocket> {
+    let domain = match *addr {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    };
+    let sock = Socket::new(domain, Type::dgram(), Some(Protocol::udp()))?;
+    if let Some(size) = opts.recv_buffer_size {
+        sock.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = opts.send_buffer_size {
+        sock.set_send_buffer_size(size)?;
+    }
+    if let Some(ttl) = opts.ttl {
+        sock.set_ttl(ttl)?;
+    }
+    if let Some(multicast_loop) = opts.multicast_loop {
+        sock.set_multicast_loop_v4(multicast_loop)?;
+    }
+    if let Some(ttl) = opts.multicast_ttl {
+        sock.set_multicast_ttl_v4(ttl)?;
+    }
+    if let Some(reuse_addr) = opts.reuse_addr {
+        sock.set_reuse_address(reuse_addr)?;
+    }
+    sock.set_nonblocking(true)?;
+    sock.bind(&SockAddr::from(*addr))?;
+    Ok(sock.into_udp_socket())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest2::prelude::*;
+
+    #[test]
+    fn bind_std_udp_applies_receive_buffer_size() {
+        let opts = SocketOptions {
+            recv_buffer_size: Some(65536),
+            ..Default::default()
+        };
+
+        let sock = unwrap!(bind_std_udp(&addr!("127.0.0.1:0"), &opts));
+
+        let buf_size = unwrap!(Socket::from(sock).recv_buffer_size());
+        assert_that!(buf_size, greater_than_or_equal_to(65536));
+    }
+
+    #[test]
+    fn bind_std_udp_applies_ttl() {
+        let opts = SocketOptions {
+            ttl: Some(7),
+            ..Default::default()
+        };
+
+        let sock = unwrap!(bind_std_udp(&addr!("127.0.0.1:0"), &opts));
+
+        assert_that!(unwrap!(sock.ttl()), eq(7));
+    }
+
+    #[test]
+    fn bind_std_udp_applies_multicast_ttl() {
+        let opts = SocketOptions {
+            multicast_ttl: Some(4),
+            ..Default::default()
+        };
+
+        let sock = unwrap!(bind_std_udp(&addr!("0.0.0.0:0"), &opts));
+
+        assert_that!(unwrap!(sock.multicast_ttl_v4()), eq(4));
+    }
+
+    #[test]
+    fn bind_std_udp_lets_sockets_share_port_when_reuse_addr_is_set() {
+        let opts = SocketOptions {
+            reuse_addr: Some(true),
+            ..Default::default()
+        };
+
+        let sock = unwrap!(bind_std_udp(&addr!("0.0.0.0:0"), &opts));
+        let addr = unwrap!(sock.local_addr());
+
+        assert_that!(bind_std_udp(&addr, &opts).is_ok(), is(true));
+    }
+}
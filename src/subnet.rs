@@ -0,0 +1,161 @@
+//! IP subnets in CIDR notation.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// IPv4 or IPv6 subnet, e.g. `192.168.1.0/24`.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct Subnet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Subnet {
+    /// Constructs subnet from network address and prefix length. Fails, if prefix length is
+    /// longer than the address itself.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self, SubnetParseError> {
+        let max_prefix_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_prefix_len {
+            return Err(SubnetParseError::InvalidPrefixLen);
+        }
+        Ok(Self { addr, prefix_len })
+    }
+
+    /// Returns the number of leading address bits that identify the network.
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Checks if given IP address belongs to this subnet. Addresses of different family never do.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = mask_u32(self.prefix_len);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = mask_u128(self.prefix_len);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = SubnetParseError;
+
+    /// Parses subnet in CIDR notation: `<ip>/<prefix length>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '/');
+        let addr = parts
+            .next()
+            .unwrap_or("")
+            .parse()
+            .map_err(|_| SubnetParseError::InvalidAddr)?;
+        let prefix_len = parts
+            .next()
+            .ok_or(SubnetParseError::MissingPrefixLen)?
+            .parse()
+            .map_err(|_| SubnetParseError::InvalidPrefixLen)?;
+        Subnet::new(addr, prefix_len)
+    }
+}
+
+/// Subnet parsing error.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum SubnetParseError {
+    InvalidAddr,
+    MissingPrefixLen,
+    InvalidPrefixLen,
+}
+
+/// Checks if IP address belongs to any of given subnets. Empty subnet list allows everything.
+pub fn is_allowed(ip: IpAddr, subnets: &[Subnet]) -> bool {
+    subnets.is_empty() || subnets.iter().any(|subnet| subnet.contains(ip))
+}
+
+fn mask_u32(prefix_len: u8) -> u32 {
+    if prefix_len == 0 {
+        0
+    } else {
+        !0 << (32 - u32::from(prefix_len))
+    }
+}
+
+fn mask_u128(prefix_len: u8) -> u128 {
+    if prefix_len == 0 {
+        0
+    } else {
+        !0 << (128 - u32::from(prefix_len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest2::prelude::*;
+
+    mod from_str {
+        use super::*;
+
+        #[test]
+        fn it_parses_ipv4_and_ipv6_subnets() {
+            let subnet: Subnet = unwrap!("192.168.1.0/24".parse());
+            assert_that!(subnet, eq(unwrap!(Subnet::new(ip!("192.168.1.0"), 24))));
+
+            let subnet: Subnet = unwrap!("fe80::/10".parse());
+            assert_that!(subnet, eq(unwrap!(Subnet::new(ip!("fe80::"), 10))));
+        }
+
+        #[test]
+        fn it_rejects_malformed_subnets() {
+            let invalid = [
+                ("192.168.1/24", SubnetParseError::InvalidAddr),
+                ("/24", SubnetParseError::InvalidAddr),
+                ("192.168.1.0", SubnetParseError::MissingPrefixLen),
+                ("192.168.1.0/", SubnetParseError::InvalidPrefixLen),
+                ("192.168.1.0/33", SubnetParseError::InvalidPrefixLen),
+                ("::1/129", SubnetParseError::InvalidPrefixLen),
+                ("10.0.0.0/-1", SubnetParseError::InvalidPrefixLen),
+            ];
+            for &(subnet, ref expected_err) in &invalid {
+                let res = subnet.parse::<Subnet>();
+                assert_that!(res, eq(Err(expected_err.clone())));
+            }
+        }
+    }
+
+    mod contains {
+        use super::*;
+
+        #[test]
+        fn it_matches_addresses_within_prefix() {
+            let subnet: Subnet = unwrap!("192.168.1.0/24".parse());
+
+            assert_that!(subnet.contains(ip!("192.168.1.1")), is(true));
+            assert_that!(subnet.contains(ip!("192.168.1.255")), is(true));
+            assert_that!(subnet.contains(ip!("192.168.2.1")), is(false));
+            assert_that!(subnet.contains(ip!("::1")), is(false));
+        }
+
+        #[test]
+        fn zero_prefix_matches_whole_address_family() {
+            let subnet: Subnet = unwrap!("0.0.0.0/0".parse());
+
+            assert_that!(subnet.contains(ip!("10.0.0.1")), is(true));
+            assert_that!(subnet.contains(ip!("::1")), is(false));
+        }
+
+        #[test]
+        fn it_matches_ipv6_addresses() {
+            let subnet: Subnet = unwrap!("fe80::/10".parse());
+
+            assert_that!(subnet.contains(ip!("fe80::1")), is(true));
+            assert_that!(subnet.contains(ip!("2001:db8::1")), is(false));
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Utilities for testing code that consumes peer discovery. Enabled with `test-util` feature.
+
+use peer_discovery::{DiscoveryError, DiscoveryMsg, DiscoveryResponse};
+use priv_prelude::*;
+use std::io;
+use std::net::SocketAddrV4;
+use std::time::Instant;
+use tokio::net::UdpSocket;
+use tokio::timer::Delay;
+
+/// How `MockDiscoveryServer` reacts to discovery requests.
+#[derive(Debug, Clone)]
+pub enum MockBehavior {
+    /// Respond with given addresses right away.
+    Respond(Vec<SocketAddr>),
+    /// Respond with given addresses after a delay.
+    Delay(Duration, Vec<SocketAddr>),
+    /// Never respond.
+    Ignore,
+    /// Respond with given bytes as is, without encrypting them.
+    Malformed(Vec<u8>),
+}
+
+/// Fake discovery server that responds to discovery requests in a scripted way. It lets
+/// applications exercise timeout and error paths of their peer discovery code deterministically.
+///
+/// Like `DiscoveryServer`, it's a future that never resolves and has to be driven to respond.
+pub struct MockDiscoveryServer {
+    sock: UdpSocket,
+    our_pk: PublicEncryptKey,
+    port: u16,
+    behavior: MockBehavior,
+    /// Responses waiting to be sent once their delay expires.
+    pending: Vec<(Delay, SocketAddr, Vec<u8>)>,
+}
+
+impl MockDiscoveryServer {
+    /// Constructs mock server listening for discovery requests on a given port. Use port 0 to
+    /// bind to a random port.
+    pub fn new(port: u16, our_pk: PublicEncryptKey, behavior: MockBehavior) -> io::Result<Self> {
+        let sock = UdpSocket::bind(&SocketAddr::V4(SocketAddrV4::new(ipv4!("0.0.0.0"), port)))?;
+        let port = sock.local_addr()?.port();
+        Ok(Self {
+            sock,
+            our_pk,
+            port,
+            behavior,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Returns server port.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Changes how subsequent requests will be handled.
+    pub fn set_behavior(&mut self, behavior: MockBehavior) {
+        self.behavior = behavior;
+    }
+
+    fn poll_requests(&mut self) -> io::Result<()> {
+        let mut buf = vec![0u8; 65000];
+        loop {
+            match self.sock.poll_recv_from(&mut buf)? {
+                Async::Ready((bytes_read, sender_addr)) => {
+                    if let Ok(DiscoveryMsg::Request(their_pk)) =
+                        DiscoveryMsg::deserialize(&buf[..bytes_read])
+                    {
+                        self.on_request(&their_pk, sender_addr);
+                    }
+                }
+                Async::NotReady => return Ok(()),
+            }
+        }
+    }
+
+    fn on_request(&mut self, their_pk: &PublicEncryptKey, sender_addr: SocketAddr) {
+        let (delay, resp) = match self.behavior {
+            MockBehavior::Respond(ref addrs) => {
+                (Duration::from_secs(0), self.make_response(their_pk, addrs))
+            }
+            MockBehavior::Delay(delay, ref addrs) => (delay, self.make_response(their_pk, addrs)),
+            MockBehavior::Ignore => return,
+            MockBehavior::Malformed(ref data) => (Duration::from_secs(0), Ok(data.clone())),
+        };
+        match resp {
+            Ok(resp) => self
+                .pending
+                .push((Delay::new(Instant::now() + delay), sender_addr, resp)),
+            Err(e) => warn!(
+                "Mock discovery server failed to make response to {}: {:?}",
+                sender_addr, e
+            ),
+        }
+    }
+
+    fn make_response(
+        &self,
+        their_pk: &PublicEncryptKey,
+        addrs: &[SocketAddr],
+    ) -> Result<Vec<u8>, DiscoveryError> {
+        let resp = DiscoveryResponse {
+            pub_key: self.our_pk,
+            addrs: addrs.to_vec(),
+            app_data: Vec::new(),
+        };
+        DiscoveryMsg::encrypted_response(their_pk, resp)
+    }
+
+    fn poll_send_responses(&mut self) -> io::Result<()> {
+        let mut i = 0;
+        while i < self.pending.len() {
+            let ready = match self.pending[i].0.poll() {
+                Ok(Async::Ready(())) | Err(_) => true,
+                Ok(Async::NotReady) => false,
+            };
+            if !ready {
+                i += 1;
+                continue;
+            }
+            let (_, addr, ref resp) = self.pending[i];
+            match self.sock.poll_send_to(resp, &addr)? {
+                Async::Ready(_) => {
+                    let _ = self.pending.remove(i);
+                }
+                Async::NotReady => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Future for MockDiscoveryServer {
+    type Item = Void;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        self.poll_requests()?;
+        self.poll_send_responses()?;
+        Ok(Async::NotReady)
+    }
+}
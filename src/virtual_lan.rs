@@ -0,0 +1,97 @@
+//! Virtual LAN for tests: simulates multiple hosts and network interfaces on a single machine, so
+//! broadcast discovery can be tested deterministically, without relying on the real network.
+//!
+//! Hosts are discovery servers sharing one port on the wildcard address. Linux delivers datagrams
+//! sent to `127.255.255.255` to every such socket, the same way broadcast on a real LAN reaches
+//! every host. Virtual interfaces are fake interfaces with that broadcast address given to
+//! discovery via `DiscoveryConfig::interfaces`. Each `VirtualLan` uses its own port, so LANs are
+//! isolated from each other.
+//!
+//! Other systems don't deliver loopback broadcast to every socket, so the module and the tests
+//! using it are Linux only. Hosts on different subnets of a routed network can't be simulated
+//! this way either: every virtual interface shares the same loopback broadcast address.
+
+use get_if_addrs::{IfAddr, Ifv4Addr, Interface};
+use peer_discovery::{DiscoveryConfig, DiscoveryServer, InterfaceSource};
+use priv_prelude::*;
+use socket::SocketOptions;
+use std::net::Ipv4Addr;
+use tokio::net::UdpSocket;
+use tokio::runtime::current_thread::Runtime;
+
+/// Address datagrams are broadcast to on virtual LAN.
+pub const LAN_BROADCAST: Ipv4Addr = Ipv4Addr::new(127, 255, 255, 255);
+
+/// Shared network segment virtual hosts and interfaces are attached to.
+pub struct VirtualLan {
+    port: u16,
+    interfaces: Vec<Interface>,
+}
+
+impl VirtualLan {
+    /// Constructs LAN on a free port with no interfaces attached.
+    pub fn new() -> Self {
+        let sock = unwrap!(UdpSocket::bind(&addr!("0.0.0.0:0")));
+        Self {
+            port: unwrap!(sock.local_addr()).port(),
+            interfaces: Vec::new(),
+        }
+    }
+
+    /// Returns the port discovery runs on within this LAN.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Attaches interface with a given name and IP to the LAN.
+    pub fn add_interface(&mut self, name: &str, ip: Ipv4Addr) {
+        self.interfaces.push(Interface {
+            name: name.to_owned(),
+            addr: IfAddr::V4(Ifv4Addr {
+                ip,
+                netmask: ipv4!("255.255.255.0"),
+                broadcast: Some(LAN_BROADCAST),
+            }),
+        });
+    }
+
+    /// Returns the interfaces attached to the LAN.
+    pub fn interfaces(&self) -> Vec<Interface> {
+        self.interfaces.clone()
+    }
+
+    /// Returns discovery config that broadcasts on interfaces attached to the LAN and lets
+    /// multiple hosts listen on the LAN port.
+    pub fn config(&self) -> DiscoveryConfig {
+        let interfaces = self.interfaces.clone();
+        DiscoveryConfig {
+            interfaces: InterfaceSource::new(move || Ok(interfaces.clone())),
+            socket_options: SocketOptions {
+                reuse_addr: Some(true),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Starts host on the LAN that responds to discovery requests with given addresses. The host
+    /// runs as long as the given event loop does.
+    pub fn spawn_host(
+        &self,
+        evloop: &mut Runtime,
+        our_addrs: Vec<SocketAddr>,
+        our_pk: &PublicEncryptKey,
+    ) {
+        let server = unwrap!(DiscoveryServer::with_config(
+            self.port,
+            our_addrs,
+            our_pk,
+            &self.config(),
+        ));
+        evloop.spawn(
+            server
+                .map(|_| ())
+                .map_err(|e| panic!("Virtual host failed: {:?}", e)),
+        );
+    }
+}
@@ -0,0 +1,46 @@
+//! Hooks to observe raw discovery datagrams, meant for protocol debugging and capturing wire
+//! format in tests. Enabled with `wire-tap` feature, so release builds don't pay for it.
+//!
+//! Be careful where tap output goes: captured datagrams contain public keys and may carry sensitive
+//! application data.
+
+use priv_prelude::*;
+use std::fmt;
+use std::sync::Arc;
+
+/// Whether datagram was sent or received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+type OnWireBytes = dyn Fn(Direction, &SocketAddr, &[u8]) + Send + Sync;
+
+/// Callback invoked with every discovery datagram exactly as it appears on the wire and with
+/// the remote address.
+#[derive(Clone)]
+pub struct WireTap(Arc<OnWireBytes>);
+
+impl WireTap {
+    /// Wraps given callback.
+    pub fn new<F>(on_wire_bytes: F) -> Self
+    where
+        F: Fn(Direction, &SocketAddr, &[u8]) + Send + Sync + 'static,
+    {
+        WireTap(Arc::new(on_wire_bytes))
+    }
+}
+
+impl fmt::Debug for WireTap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WireTap")
+    }
+}
+
+/// Passes datagram to the tap, if there is one.
+pub fn observe(tap: &Option<WireTap>, direction: Direction, addr: &SocketAddr, bytes: &[u8]) {
+    if let Some(ref tap) = *tap {
+        (tap.0)(direction, addr, bytes);
+    }
+}